@@ -36,7 +36,7 @@ impl Ollama {
     let serialized = serde_json::to_string(&request)
       .map_err(|e| e.to_string())
       .unwrap();
-    let builder = self.reqwest_client.post(url);
+    let builder = self.request(reqwest::Method::POST, url);
 
     let res = builder.body(serialized).send().await?;
 
@@ -77,7 +77,7 @@ impl Ollama {
 
     let url = format!("{}api/chat", self.url_str());
     let serialized = serde_json::to_string(&request)?;
-    let builder = self.reqwest_client.post(url);
+    let builder = self.request(reqwest::Method::POST, url);
 
     let res = builder.body(serialized).send().await?;
 
@@ -182,12 +182,14 @@ pub struct ChatMessageResponse {
 pub struct ChatMessageFinalResponseData {
   /// Time spent generating the response
   pub total_duration: u64,
-  /// Number of tokens in the prompt
-  pub prompt_eval_count: u16,
+  /// Number of tokens in the prompt. Absent if the prompt was cached
+  #[serde(default)]
+  pub prompt_eval_count: u32,
   /// Time spent in nanoseconds evaluating the prompt
   pub prompt_eval_duration: u64,
   /// Number of tokens the response
-  pub eval_count: u16,
+  #[serde(default)]
+  pub eval_count: u32,
   /// Time in nanoseconds spent generating the response
   pub eval_duration: u64,
 }
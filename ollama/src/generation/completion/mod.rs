@@ -32,7 +32,7 @@ impl Ollama {
 
     let url = format!("{}api/generate", self.url_str());
     let serialized = serde_json::to_string(&request)?;
-    let builder = self.reqwest_client.post(url);
+    let builder = self.request(reqwest::Method::POST, url);
 
     let res = builder.body(serialized).send().await?;
 
@@ -70,7 +70,7 @@ impl Ollama {
 
     let url = format!("{}api/generate", self.url_str());
     let serialized = serde_json::to_string(&request)?;
-    let builder = self.reqwest_client.post(url);
+    let builder = self.request(reqwest::Method::POST, url);
 
     let res = builder.body(serialized).send().await?;
 
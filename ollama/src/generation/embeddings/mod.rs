@@ -16,7 +16,7 @@ impl Ollama {
   ) -> crate::error::Result<GenerateEmbeddingsResponse> {
     let url = format!("{}api/embed", self.url_str());
     let serialized = serde_json::to_string(&request)?;
-    let builder = self.reqwest_client.post(url);
+    let builder = self.request(reqwest::Method::POST, url);
 
     let res = builder.body(serialized).send().await?;
 
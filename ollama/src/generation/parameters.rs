@@ -8,7 +8,7 @@ pub enum FormatType {
   Json,
 
   /// Requires Ollama 0.5.0 or greater.
-  StructuredJson(JsonStructure),
+  StructuredJson(Box<JsonStructure>),
 }
 
 impl Serialize for FormatType {
@@ -23,11 +23,17 @@ impl Serialize for FormatType {
   }
 }
 
-/// Represents a serialized JSON schema. You can create this by converting
-/// a JsonSchema:
+/// Represents a serialized JSON schema. You can create this from any type
+/// implementing JsonSchema:
 /// ```rust
-/// let json_schema = schema_for!(Output);
-/// let serialized: SerializedJsonSchema = json_schema.into();
+/// use ollama::generation::parameters::{FormatType, JsonSchema, JsonStructure};
+///
+/// #[derive(JsonSchema)]
+/// struct Output {
+///   answer: String,
+/// }
+///
+/// let format = FormatType::StructuredJson(Box::new(JsonStructure::new::<Output>()));
 /// ```
 #[derive(Debug, Clone)]
 pub struct JsonStructure {
@@ -1,5 +1,8 @@
 #![cfg_attr(docsrs, feature(doc_cfg))]
 
+use base64::{engine::general_purpose::STANDARD, Engine};
+use percent_encoding::percent_decode_str;
+use reqwest::header::{HeaderMap, HeaderValue, InvalidHeaderValue, AUTHORIZATION};
 use url::Url;
 
 pub mod coordinator;
@@ -7,6 +10,7 @@ pub mod error;
 pub mod generation;
 pub mod history;
 pub mod models;
+mod version;
 
 /// A trait to try to convert some type into a [`Url`].
 ///
@@ -65,12 +69,44 @@ impl IntoUrlSealed for String {
   }
 }
 
+/// Credentials for Ollama behind an authenticating proxy
+#[derive(Debug, Clone)]
+pub enum Auth {
+  Bearer(String),
+  Basic {
+    username: String,
+    password: Option<String>,
+  },
+}
+
+impl Auth {
+  fn header_value(&self) -> Result<HeaderValue, InvalidHeaderValue> {
+    let value = match self {
+      Auth::Bearer(token) => format!("Bearer {token}"),
+      Auth::Basic { username, password } => {
+        let credentials = format!("{username}:{}", password.as_deref().unwrap_or_default());
+
+        format!("Basic {}", STANDARD.encode(credentials))
+      }
+    };
+
+    let mut value = HeaderValue::from_str(&value)?;
+    value.set_sensitive(true);
+
+    Ok(value)
+  }
+}
+
 #[derive(Debug, Clone)]
 pub struct Ollama {
   pub(crate) url: Url,
   pub(crate) reqwest_client: reqwest::Client,
+  pub(crate) headers_hook: Option<HeadersHook>,
 }
 
+/// Adds headers to every request when it is sent, e.g. trace context of the current span
+pub type HeadersHook = fn(&mut HeaderMap);
+
 impl Ollama {
   /// # Panics
   ///
@@ -89,14 +125,71 @@ impl Ollama {
   }
 
   /// Create new instance from a [`Url`].
-  #[inline]
-  pub fn from_url(url: Url) -> Self {
-    Self {
+  ///
+  /// The path is used as a base path of all endpoints, e.g. `https://host/ollama` sends
+  /// chats to `https://host/ollama/api/chat`. Credentials in the URL are sent as basic
+  /// auth, see [`Ollama::with_auth`].
+  pub fn from_url(mut url: Url) -> Self {
+    let decode = |s: &str| percent_decode_str(s).decode_utf8_lossy().into_owned();
+
+    let auth = (!url.username().is_empty()).then(|| Auth::Basic {
+      username: decode(url.username()),
+      password: url.password().map(decode),
+    });
+
+    // keeps credentials out of logged URLs
+    let _ = url.set_username("");
+    let _ = url.set_password(None);
+
+    // without the trailing slash, endpoints would replace the last path segment
+    if !url.path().ends_with('/') {
+      url.set_path(&format!("{}/", url.path()));
+    }
+
+    let ollama = Self {
       url,
       ..Default::default()
+    };
+
+    match auth {
+      Some(auth) => ollama
+        .with_auth(auth)
+        .expect("basic auth is always a valid header"),
+      None => ollama,
     }
   }
 
+  /// Send credentials with every request
+  pub fn with_auth(mut self, auth: Auth) -> Result<Self, InvalidHeaderValue> {
+    let headers = HeaderMap::from_iter([(AUTHORIZATION, auth.header_value()?)]);
+
+    self.reqwest_client = reqwest::Client::builder()
+      .default_headers(headers)
+      .build()
+      .expect("reqwest client");
+
+    Ok(self)
+  }
+
+  /// Call `hook` to add headers to every request, see [`HeadersHook`]
+  pub fn with_headers_hook(mut self, hook: HeadersHook) -> Self {
+    self.headers_hook = Some(hook);
+    self
+  }
+
+  pub(crate) fn request(&self, method: reqwest::Method, url: String) -> reqwest::RequestBuilder {
+    let builder = self.reqwest_client.request(method, url);
+
+    let Some(hook) = self.headers_hook else {
+      return builder;
+    };
+
+    let mut headers = HeaderMap::new();
+    hook(&mut headers);
+
+    builder.headers(headers)
+  }
+
   /// Returns the http URI of the Ollama instance
   ///
   /// # Panics
@@ -143,6 +236,7 @@ impl Default for Ollama {
     Self {
       url: Url::parse("http://127.0.0.1:11434").unwrap(),
       reqwest_client: reqwest::Client::new(),
+      headers_hook: None,
     }
   }
 }
@@ -12,7 +12,7 @@ impl Ollama {
 
     let url = format!("{}api/copy", self.url_str());
     let serialized = serde_json::to_string(&request)?;
-    let builder = self.reqwest_client.post(url);
+    let builder = self.request(reqwest::Method::POST, url);
 
     let res = builder.body(serialized).send().await?;
 
@@ -25,7 +25,7 @@ impl Ollama {
 
     let url = format!("{}api/create", self.url_str());
     let serialized = serde_json::to_string(&request)?;
-    let builder = self.reqwest_client.post(url);
+    let builder = self.request(reqwest::Method::POST, url);
 
     let res = builder.body(serialized).send().await?;
 
@@ -63,7 +63,7 @@ impl Ollama {
   ) -> crate::error::Result<CreateModelStatus> {
     let url = format!("{}api/create", self.url_str());
     let serialized = serde_json::to_string(&request)?;
-    let builder = self.reqwest_client.post(url);
+    let builder = self.request(reqwest::Method::POST, url);
 
     let res = builder.body(serialized).send().await?;
 
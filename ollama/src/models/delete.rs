@@ -9,7 +9,7 @@ impl Ollama {
 
     let url = format!("{}api/delete", self.url_str());
     let serialized = serde_json::to_string(&request)?;
-    let builder = self.reqwest_client.delete(url);
+    let builder = self.request(reqwest::Method::DELETE, url);
 
     let res = builder.body(serialized).send().await?;
 
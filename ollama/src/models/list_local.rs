@@ -7,7 +7,7 @@ use super::LocalModel;
 impl Ollama {
   pub async fn list_local_models(&self) -> crate::error::Result<Vec<LocalModel>> {
     let url = format!("{}api/tags", self.url_str());
-    let builder = self.reqwest_client.get(url);
+    let builder = self.request(reqwest::Method::GET, url);
 
     let res = builder.send().await?;
 
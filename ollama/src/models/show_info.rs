@@ -9,7 +9,7 @@ impl Ollama {
   pub async fn show_model_info(&self, model_name: String) -> crate::error::Result<ModelInfo> {
     let url = format!("{}api/show", self.url_str());
     let serialized = serde_json::to_string(&ModelInfoRequest { model_name })?;
-    let builder = self.reqwest_client.post(url);
+    let builder = self.request(reqwest::Method::POST, url);
 
     let res = builder.body(serialized).send().await?;
 
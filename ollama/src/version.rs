@@ -0,0 +1,27 @@
+use serde::Deserialize;
+
+use crate::{error::OllamaError, Ollama};
+
+impl Ollama {
+  /// Version of the Ollama server. Cheaper than listing models, e.g. for health checks
+  pub async fn version(&self) -> crate::error::Result<String> {
+    let url = format!("{}api/version", self.url_str());
+    let builder = self.request(reqwest::Method::GET, url);
+
+    let res = builder.send().await?;
+
+    if !res.status().is_success() {
+      return Err(OllamaError::Other(res.text().await?));
+    }
+
+    let res = res.bytes().await?;
+    let res = serde_json::from_slice::<VersionResponse>(&res)?;
+
+    Ok(res.version)
+  }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct VersionResponse {
+  version: String,
+}
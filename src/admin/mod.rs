@@ -0,0 +1,58 @@
+//! Admin API
+//!
+//! The first admin is promoted with `robo admin promote <email>`, or signs up through
+//! OIDC with an email from [crate::config::AuthConfig::admin_emails]
+
+mod routes;
+
+use crate::{
+  config::config,
+  health, metrics,
+  result::{Error, Result},
+  user::auth,
+};
+use axum::{
+  middleware::from_fn,
+  routing::{get, patch, post, put},
+  Router,
+};
+
+pub fn admin_router() -> Router {
+  let router = Router::new()
+    .route("/admin/users", get(routes::get_users))
+    .route("/admin/users/{user_id}", patch(routes::edit_user))
+    .route(
+      "/admin/users/{user_id}/tokens/revoke",
+      post(routes::revoke_user_tokens),
+    )
+    .route("/admin/users/{user_id}/usage", get(routes::get_user_usage))
+    .route(
+      "/admin/users/{user_id}/limits",
+      get(routes::get_user_limits),
+    )
+    .route(
+      "/admin/users/{user_id}/limits",
+      put(routes::set_user_limits),
+    )
+    .route("/admin/users/{user_id}/move", post(routes::move_user))
+    .route("/admin/readyz", get(health::admin_readyz));
+
+  // metrics contain backend urls, so they are not public on the main listener
+  let router = if metrics::enabled() && config().metrics.port == 0 {
+    router.route("/admin/metrics", get(metrics::admin_render))
+  } else {
+    router
+  };
+
+  router.layer(from_fn(auth::auth_middleware))
+}
+
+/// Run `admin` CLI command. `args` are the arguments after `admin`
+pub async fn cli(args: &[String]) -> Result {
+  let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+  match args.as_slice() {
+    ["promote", email] => routes::promote(email).await,
+    _ => Err(Error::Usage("admin promote <email>".into())),
+  }
+}
@@ -0,0 +1,352 @@
+//! Admin API routes
+
+use crate::{
+  audit::{schemas::AuditAction, AuditEntry, ClientInfo},
+  chat::schemas::{ChatIden, MessageIden},
+  config::config,
+  db::{
+    for_each_shard, postgres,
+    reshard::{self, MoveUserReport},
+    shard,
+  },
+  extract::{Json, Path, Query as QueryParams},
+  pagination::Pagination,
+  result::{Error, Result},
+  user::{
+    auth::{self, Admin},
+    directory,
+    limits::{self, LimitOverrides, Limits},
+    schemas::{UserIden, UserLimitsIden, UserRole},
+  },
+};
+use sea_query::{Expr, Order, PostgresQueryBuilder, Query};
+use serde::{Deserialize, Serialize};
+use sqlx::{query, query_as, FromRow, Row};
+use std::net::Ipv4Addr;
+use tracing::{info, instrument};
+use ts_rs::TS;
+use validator::Validate;
+
+#[derive(TS, Debug, Serialize, FromRow)]
+#[ts(export, export_to = "./index.ts")]
+pub struct AdminUser {
+  id: i32,
+  name: String,
+  email: String,
+  #[sqlx(try_from = "i16")]
+  role: UserRole,
+  disabled: bool,
+}
+
+/// list users across all shards ordered by id
+#[instrument(name = "admin::get_users")]
+pub async fn get_users(
+  Admin(_): Admin,
+  QueryParams(pagination): QueryParams<Pagination>,
+) -> Result<Json<Vec<AdminUser>>> {
+  pagination.validate()?;
+
+  // every shard may contain the whole requested page, so each of them must return
+  // everything up to the end of the page
+  let users_query = Query::select()
+    .from(UserIden::Table)
+    .columns([
+      UserIden::Id,
+      UserIden::Name,
+      UserIden::Email,
+      UserIden::Role,
+      UserIden::Disabled,
+    ])
+    .order_by(UserIden::Id, Order::Asc)
+    .limit(pagination.offset() + pagination.limit())
+    .to_string(PostgresQueryBuilder);
+
+  let shard_users =
+    for_each_shard(|db| query_as::<_, AdminUser>(&users_query).fetch_all(db)).await?;
+
+  let mut users: Vec<_> = shard_users.into_iter().flatten().collect();
+  users.sort_unstable_by_key(|user| user.id);
+
+  let users = users
+    .into_iter()
+    .skip(pagination.offset() as usize)
+    .take(pagination.limit() as usize)
+    .collect();
+
+  Ok(Json(users))
+}
+
+#[derive(TS, Debug, Deserialize)]
+#[ts(export, export_to = "./index.ts")]
+pub struct EditUserRequest {
+  #[ts(optional)]
+  role: Option<UserRole>,
+  #[ts(optional)]
+  disabled: Option<bool>,
+}
+
+/// change user role or disable/enable the account. applies to existing tokens within
+/// seconds
+#[instrument(name = "admin::edit_user")]
+pub async fn edit_user(
+  Admin(admin_id): Admin,
+  client: ClientInfo,
+  Path(user_id): Path<i32>,
+  Json(edit): Json<EditUserRequest>,
+) -> Result<()> {
+  // protects from locking out the last admin
+  if user_id == admin_id {
+    return Err(Error::Forbidden);
+  }
+
+  if edit.role.is_none() && edit.disabled.is_none() {
+    return Ok(());
+  }
+
+  let user_update_query = {
+    let mut user_update_query = Query::update();
+
+    user_update_query
+      .table(UserIden::Table)
+      .and_where(Expr::col(UserIden::Id).eq(user_id));
+
+    if let Some(role) = edit.role {
+      user_update_query.value(UserIden::Role, role);
+    }
+
+    if let Some(disabled) = edit.disabled {
+      user_update_query.value(UserIden::Disabled, disabled);
+    }
+
+    user_update_query.to_string(PostgresQueryBuilder)
+  };
+
+  let db = postgres(user_id)?;
+  let res = query(&user_update_query).execute(db).await?;
+
+  if res.rows_affected() == 0 {
+    return Err(Error::NotFound);
+  }
+
+  auth::invalidate_account(user_id).await;
+
+  info!(admin_id, user_id, ?edit, "user edited by admin");
+
+  AuditEntry::new(user_id, AuditAction::AdminUserEdited)
+    .actor(admin_id)
+    .details(format!("{edit:?}"))
+    .record(&client)
+    .await;
+
+  Ok(())
+}
+
+/// Grant the admin role to the user with the email, see [super::cli]
+pub async fn promote(email: &str) -> Result {
+  let Some((user_id, shard_index)) = directory::lookup(email).await? else {
+    return Err(Error::NotFound);
+  };
+
+  let promote_query = Query::update()
+    .table(UserIden::Table)
+    .value(UserIden::Role, UserRole::Admin)
+    .and_where(Expr::col(UserIden::Id).eq(user_id))
+    .to_string(PostgresQueryBuilder);
+
+  let res = query(&promote_query).execute(shard(shard_index)?).await?;
+
+  if res.rows_affected() == 0 {
+    return Err(Error::NotFound);
+  }
+
+  auth::invalidate_account(user_id).await;
+
+  info!(user_id, "user promoted to admin from the command line");
+
+  let client = ClientInfo {
+    ip: Ipv4Addr::LOCALHOST.into(),
+    user_agent: Some("robo cli".to_string()),
+  };
+
+  AuditEntry::new(user_id, AuditAction::AdminUserEdited)
+    .details("promoted to admin from the command line")
+    .record(&client)
+    .await;
+
+  Ok(())
+}
+
+/// sign the user out everywhere
+#[instrument(name = "admin::revoke_user_tokens")]
+pub async fn revoke_user_tokens(
+  Admin(admin_id): Admin,
+  client: ClientInfo,
+  Path(user_id): Path<i32>,
+) -> Result<()> {
+  auth::revoke_tokens(user_id).await?;
+
+  info!(admin_id, user_id, "user tokens revoked by admin");
+
+  AuditEntry::new(user_id, AuditAction::TokensRevoked)
+    .actor(admin_id)
+    .record(&client)
+    .await;
+
+  Ok(())
+}
+
+#[derive(TS, Debug, Serialize)]
+#[ts(export, export_to = "./index.ts")]
+pub struct UserUsage {
+  user_id: i32,
+  #[ts(type = "number")]
+  chats: i64,
+  #[ts(type = "number")]
+  messages: i64,
+}
+
+/// get user usage stats
+#[instrument(name = "admin::get_user_usage")]
+pub async fn get_user_usage(Admin(_): Admin, Path(user_id): Path<i32>) -> Result<Json<UserUsage>> {
+  let db = postgres(user_id)?;
+
+  let user_query = Query::select()
+    .from(UserIden::Table)
+    .column(UserIden::Id)
+    .and_where(Expr::col(UserIden::Id).eq(user_id))
+    .to_string(PostgresQueryBuilder);
+
+  if query(&user_query).fetch_optional(db).await?.is_none() {
+    return Err(Error::NotFound);
+  }
+
+  let usage_query = Query::select()
+    .expr(Expr::col((ChatIden::Table, ChatIden::Id)).count_distinct())
+    .expr(Expr::col((MessageIden::Table, MessageIden::Id)).count())
+    .from(ChatIden::Table)
+    .left_join(
+      MessageIden::Table,
+      Expr::col((MessageIden::Table, MessageIden::ChatId)).equals((ChatIden::Table, ChatIden::Id)),
+    )
+    .and_where(Expr::col((ChatIden::Table, ChatIden::UserId)).eq(user_id))
+    .to_string(PostgresQueryBuilder);
+
+  let row = query(&usage_query).fetch_one(db).await?;
+
+  Ok(Json(UserUsage {
+    user_id,
+    chats: row.get(0),
+    messages: row.get(1),
+  }))
+}
+
+#[derive(TS, Debug, Serialize)]
+#[ts(export, export_to = "./index.ts")]
+pub struct UserLimits {
+  overrides: LimitOverrides,
+  /// Overrides applied to the defaults
+  effective: Limits,
+}
+
+impl From<LimitOverrides> for UserLimits {
+  fn from(overrides: LimitOverrides) -> Self {
+    Self {
+      overrides,
+      effective: overrides.apply(&config().limits),
+    }
+  }
+}
+
+/// get rate limits of a user
+#[instrument(name = "admin::get_user_limits")]
+pub async fn get_user_limits(
+  Admin(_): Admin,
+  Path(user_id): Path<i32>,
+) -> Result<Json<UserLimits>> {
+  let Some(overrides) = limits::load_overrides(user_id).await? else {
+    return Err(Error::NotFound);
+  };
+
+  Ok(Json(overrides.into()))
+}
+
+/// override rate limits of a user. absent limits are reset to the defaults
+#[instrument(name = "admin::set_user_limits")]
+pub async fn set_user_limits(
+  Admin(admin_id): Admin,
+  client: ClientInfo,
+  Path(user_id): Path<i32>,
+  Json(overrides): Json<LimitOverrides>,
+) -> Result<Json<UserLimits>> {
+  overrides.validate()?;
+
+  let limits_update_query = Query::update()
+    .table(UserLimitsIden::Table)
+    .values([
+      (
+        UserLimitsIden::LimitRequestsPerMinute,
+        overrides.requests_per_minute.into(),
+      ),
+      (
+        UserLimitsIden::LimitConcurrentGenerations,
+        overrides.concurrent_generations.into(),
+      ),
+      (
+        UserLimitsIden::LimitDailyTokens,
+        overrides.daily_tokens.into(),
+      ),
+    ])
+    .and_where(Expr::col(UserLimitsIden::Id).eq(user_id))
+    .to_string(PostgresQueryBuilder);
+
+  let db = postgres(user_id)?;
+  let res = query(&limits_update_query).execute(db).await?;
+
+  if res.rows_affected() == 0 {
+    return Err(Error::NotFound);
+  }
+
+  limits::invalidate_overrides(user_id).await;
+
+  info!(
+    admin_id,
+    user_id,
+    ?overrides,
+    "user limits changed by admin"
+  );
+
+  AuditEntry::new(user_id, AuditAction::AdminUserEdited)
+    .actor(admin_id)
+    .details(format!("{overrides:?}"))
+    .record(&client)
+    .await;
+
+  Ok(Json(overrides.into()))
+}
+
+#[derive(TS, Debug, Deserialize)]
+#[ts(export, export_to = "./index.ts")]
+pub struct MoveUserRequest {
+  /// Zero based target shard index
+  shard: usize,
+}
+
+/// move user with all their data to another shard
+#[instrument(name = "admin::move_user")]
+pub async fn move_user(
+  Admin(admin_id): Admin,
+  client: ClientInfo,
+  Path(user_id): Path<i32>,
+  Json(req): Json<MoveUserRequest>,
+) -> Result<Json<MoveUserReport>> {
+  let report = reshard::move_user(user_id, req.shard).await?;
+
+  // recorded after the move, so the entry lands on the new shard
+  AuditEntry::new(user_id, AuditAction::AdminUserMoved)
+    .actor(admin_id)
+    .details(format!("{report:?}"))
+    .record(&client)
+    .await;
+
+  Ok(Json(report))
+}
@@ -0,0 +1,209 @@
+//! Security audit log
+//!
+//! Authentication and admin actions are appended to the `audit_log` table on the shard of
+//! the user they are about.
+
+mod routes;
+pub mod schemas;
+
+use crate::{
+  config::config,
+  db::postgres,
+  result::{Error, Result},
+  user::auth,
+};
+use axum::{
+  extract::{ConnectInfo, FromRequestParts},
+  http::{
+    header::{FORWARDED, USER_AGENT},
+    request::Parts,
+    HeaderMap,
+  },
+  middleware::from_fn,
+  routing::get,
+  Router,
+};
+use schemas::{AuditAction, AuditLogIden};
+use sea_query::{PostgresQueryBuilder, Query};
+use sqlx::query;
+use std::net::{IpAddr, SocketAddr};
+use tracing::{error, instrument};
+
+fn is_trusted_proxy(ip: IpAddr) -> bool {
+  config()
+    .server
+    .trusted_proxies
+    .iter()
+    .any(|proxy| proxy.contains(&ip))
+}
+
+/// Parse a node of `Forwarded: for=...` or `X-Forwarded-For`. Ports are dropped,
+/// obfuscated and `unknown` nodes are `None`
+fn parse_node(node: &str) -> Option<IpAddr> {
+  let node = node.trim().trim_matches('"');
+
+  node
+    .parse()
+    .or_else(|_| node.parse::<SocketAddr>().map(|addr| addr.ip()))
+    .or_else(|_| node.trim_start_matches('[').trim_end_matches(']').parse())
+    .ok()
+}
+
+/// Client addresses forwarded by proxies, nearest proxy last. `Forwarded` takes
+/// precedence over `X-Forwarded-For`
+fn forwarded_chain(headers: &HeaderMap) -> Vec<&str> {
+  let values = |name: &str| {
+    headers
+      .get_all(name)
+      .iter()
+      .filter_map(|value| value.to_str().ok())
+      .flat_map(|value| value.split(','))
+      .collect::<Vec<_>>()
+  };
+
+  let forwarded: Vec<_> = values(FORWARDED.as_str())
+    .into_iter()
+    .filter_map(|element| {
+      element.split(';').find_map(|pair| {
+        let (key, value) = pair.split_once('=')?;
+        key.trim().eq_ignore_ascii_case("for").then_some(value)
+      })
+    })
+    .collect();
+
+  if !forwarded.is_empty() {
+    return forwarded;
+  }
+
+  values("x-forwarded-for")
+}
+
+/// Address of the client. Forwarded headers are only followed through trusted proxies,
+/// see [crate::config::ServerConfig::trusted_proxies], otherwise any client could spoof
+/// its address
+fn client_ip(peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+  let mut ip = peer;
+
+  for node in forwarded_chain(headers).into_iter().rev() {
+    if !is_trusted_proxy(ip) {
+      break;
+    }
+
+    // the proxy address is the best we have if it forwarded garbage
+    let Some(forwarded) = parse_node(node) else {
+      break;
+    };
+
+    ip = forwarded;
+  }
+
+  ip
+}
+
+/// Extracts client IP and user agent
+#[derive(Debug, Clone)]
+pub struct ClientInfo {
+  pub ip: IpAddr,
+  pub user_agent: Option<String>,
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for ClientInfo {
+  type Rejection = Error;
+
+  async fn from_request_parts(req: &mut Parts, _: &S) -> Result<Self> {
+    let Some(ConnectInfo(addr)) = req.extensions.get::<ConnectInfo<SocketAddr>>() else {
+      error!("Client address is missing. Most likely the app is served without connect info");
+
+      return Err(Error::Unauthorized);
+    };
+
+    let user_agent = req
+      .headers
+      .get(USER_AGENT)
+      .and_then(|user_agent| user_agent.to_str().ok())
+      .map(|user_agent| user_agent.chars().take(255).collect());
+
+    Ok(Self {
+      ip: client_ip(addr.ip(), &req.headers),
+      user_agent,
+    })
+  }
+}
+
+/// Builder for an audit log entry
+#[derive(Debug)]
+pub struct AuditEntry {
+  user_id: i32,
+  action: AuditAction,
+  actor_id: Option<i32>,
+  details: Option<String>,
+}
+
+impl AuditEntry {
+  pub fn new(user_id: i32, action: AuditAction) -> Self {
+    Self {
+      user_id,
+      action,
+      actor_id: None,
+      details: None,
+    }
+  }
+
+  /// Set who performed the action if not the user
+  pub fn actor(mut self, actor_id: i32) -> Self {
+    self.actor_id = Some(actor_id);
+    self
+  }
+
+  pub fn details(mut self, details: impl Into<String>) -> Self {
+    self.details = Some(details.into());
+    self
+  }
+
+  /// Append the entry to the log.
+  ///
+  /// Failing to write the log must not break authentication, so errors are only logged
+  #[instrument(name = "audit::record")]
+  pub async fn record(self, client: &ClientInfo) {
+    let insert_entry_query = Query::insert()
+      .into_table(AuditLogIden::Table)
+      .columns([
+        AuditLogIden::UserId,
+        AuditLogIden::ActorId,
+        AuditLogIden::Action,
+        AuditLogIden::Ip,
+        AuditLogIden::UserAgent,
+        AuditLogIden::Details,
+      ])
+      .values_panic([
+        self.user_id.into(),
+        self.actor_id.into(),
+        self.action.into(),
+        client.ip.to_string().into(),
+        client.user_agent.clone().into(),
+        self.details.into(),
+      ])
+      .to_string(PostgresQueryBuilder);
+
+    let db = match postgres(self.user_id) {
+      Ok(db) => db,
+      Err(e) => {
+        error!("{e}");
+        return;
+      }
+    };
+
+    let res = query(&insert_entry_query).execute(db).await;
+
+    if let Err(e) = res {
+      error!("{e}");
+    }
+  }
+}
+
+pub fn audit_router() -> Router {
+  Router::new()
+    .route("/me/audit", get(routes::get_my_audit_log))
+    .route("/admin/audit", get(routes::get_audit_log))
+    .layer(from_fn(auth::auth_middleware))
+}
@@ -0,0 +1,101 @@
+//! Audit log API routes
+
+use super::schemas::{AuditLog, AuditLogIden};
+use crate::{
+  db::{for_each_shard, postgres_read},
+  extract::{Json, Query as QueryParams},
+  pagination::Pagination,
+  result::Result,
+  user::auth::{Admin, Auth},
+};
+use sea_query::{Expr, Order, PostgresQueryBuilder, Query, SelectStatement};
+use serde::Deserialize;
+use sqlx::query_as;
+use std::cmp::Reverse;
+use tracing::instrument;
+use ts_rs::TS;
+use validator::Validate;
+
+fn select_entries() -> SelectStatement {
+  Query::select()
+    .from(AuditLogIden::Table)
+    .columns([
+      AuditLogIden::Id,
+      AuditLogIden::UserId,
+      AuditLogIden::ActorId,
+      AuditLogIden::Action,
+      AuditLogIden::Ip,
+      AuditLogIden::UserAgent,
+      AuditLogIden::Details,
+      AuditLogIden::CreatedAt,
+    ])
+    .to_owned()
+}
+
+async fn user_entries(user_id: i32, pagination: &Pagination) -> Result<Vec<AuditLog>> {
+  let entries_query = select_entries()
+    .and_where(Expr::col(AuditLogIden::UserId).eq(user_id))
+    .order_by(AuditLogIden::Id, Order::Desc)
+    .limit(pagination.limit())
+    .offset(pagination.offset())
+    .to_string(PostgresQueryBuilder);
+
+  let db = postgres_read(user_id).await?;
+
+  let entries = query_as(&entries_query).fetch_all(db).await?;
+
+  Ok(entries)
+}
+
+/// get own audit log, newest first
+#[instrument(name = "audit::get_my_audit_log")]
+pub async fn get_my_audit_log(
+  Auth(user_id): Auth,
+  QueryParams(pagination): QueryParams<Pagination>,
+) -> Result<Json<Vec<AuditLog>>> {
+  pagination.validate()?;
+
+  Ok(Json(user_entries(user_id, &pagination).await?))
+}
+
+#[derive(TS, Debug, Deserialize)]
+#[ts(export, export_to = "./index.ts")]
+pub struct AuditLogFilter {
+  /// Only entries about this user. All users if absent
+  #[ts(optional)]
+  user_id: Option<i32>,
+}
+
+/// get audit log of a user or of all users across all shards, newest first
+#[instrument(name = "audit::get_audit_log")]
+pub async fn get_audit_log(
+  Admin(_): Admin,
+  QueryParams(filter): QueryParams<AuditLogFilter>,
+  QueryParams(pagination): QueryParams<Pagination>,
+) -> Result<Json<Vec<AuditLog>>> {
+  pagination.validate()?;
+
+  if let Some(user_id) = filter.user_id {
+    return Ok(Json(user_entries(user_id, &pagination).await?));
+  }
+
+  // every shard may contain the whole requested page
+  let entries_query = select_entries()
+    .order_by(AuditLogIden::CreatedAt, Order::Desc)
+    .limit(pagination.offset() + pagination.limit())
+    .to_string(PostgresQueryBuilder);
+
+  let shard_entries =
+    for_each_shard(|db| query_as::<_, AuditLog>(&entries_query).fetch_all(db)).await?;
+
+  let mut entries: Vec<_> = shard_entries.into_iter().flatten().collect();
+  entries.sort_unstable_by_key(|entry| Reverse(entry.created_at));
+
+  let entries = entries
+    .into_iter()
+    .skip(pagination.offset() as usize)
+    .take(pagination.limit() as usize)
+    .collect();
+
+  Ok(Json(entries))
+}
@@ -0,0 +1,125 @@
+//! Audit log DB schemas
+
+use crate::result::{Error, Result};
+use chrono::{DateTime, Utc};
+use sea_query::{enum_def, ColumnDef, Expr, Index, PostgresQueryBuilder, Table, Value};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use ts_rs::TS;
+
+#[derive(TS, Debug, Clone, Copy, Deserialize, Serialize)]
+#[ts(export, export_to = "./index.ts")]
+pub enum AuditAction {
+  Register,
+  Login,
+  LoginFailed,
+  TwoFactorEnabled,
+  TwoFactorDisabled,
+  TwoFactorFailed,
+  ChatDeleted,
+  AdminUserEdited,
+  AdminUserMoved,
+  PasswordChanged,
+  /// All tokens of the user were revoked, by the user or an admin
+  TokensRevoked,
+}
+
+impl AuditAction {
+  /// Convert an i16 to an AuditAction. Required for deserializing from DB.
+  pub fn from_i16(action: i16) -> Result<Self> {
+    match action {
+      0 => Ok(AuditAction::Register),
+      1 => Ok(AuditAction::Login),
+      2 => Ok(AuditAction::LoginFailed),
+      3 => Ok(AuditAction::TwoFactorEnabled),
+      4 => Ok(AuditAction::TwoFactorDisabled),
+      5 => Ok(AuditAction::TwoFactorFailed),
+      6 => Ok(AuditAction::ChatDeleted),
+      7 => Ok(AuditAction::AdminUserEdited),
+      8 => Ok(AuditAction::AdminUserMoved),
+      9 => Ok(AuditAction::PasswordChanged),
+      10 => Ok(AuditAction::TokensRevoked),
+      _ => Err(Error::InvalidAuditAction),
+    }
+  }
+}
+
+impl TryFrom<i16> for AuditAction {
+  type Error = Error;
+
+  fn try_from(action: i16) -> Result<Self> {
+    Self::from_i16(action)
+  }
+}
+
+impl From<AuditAction> for Value {
+  fn from(action: AuditAction) -> Self {
+    Value::SmallInt(Some(action as i16))
+  }
+}
+
+/// Append-only log entry. Stored on the shard of the user it is about
+#[enum_def]
+#[derive(TS, Debug, Serialize, FromRow)]
+#[ts(export, export_to = "./index.ts")]
+pub struct AuditLog {
+  pub id: i32,
+  pub user_id: i32,
+  /// Who performed the action if not the user, e.g. an admin
+  pub actor_id: Option<i32>,
+  #[sqlx(try_from = "i16")]
+  pub action: AuditAction,
+  pub ip: Option<String>,
+  pub user_agent: Option<String>,
+  pub details: Option<String>,
+  pub created_at: DateTime<Utc>,
+}
+
+/// Statements of the `create_audit_tables` migration. See [crate::db::migrations]
+pub fn create_tables() -> Vec<String> {
+  // no foreign key, entries must outlive the user
+  let audit_log_table = Table::create()
+    .table(AuditLogIden::Table)
+    .if_not_exists()
+    .col(
+      ColumnDef::new(AuditLogIden::Id)
+        .integer()
+        .not_null()
+        .auto_increment()
+        .primary_key(),
+    )
+    .col(ColumnDef::new(AuditLogIden::UserId).integer().not_null())
+    .col(ColumnDef::new(AuditLogIden::ActorId).integer().null())
+    .col(
+      ColumnDef::new(AuditLogIden::Action)
+        .small_integer()
+        .not_null(),
+    )
+    .col(ColumnDef::new(AuditLogIden::Ip).string().null())
+    .col(ColumnDef::new(AuditLogIden::UserAgent).string().null())
+    .col(ColumnDef::new(AuditLogIden::Details).text().null())
+    .col(
+      ColumnDef::new(AuditLogIden::CreatedAt)
+        .timestamp_with_time_zone()
+        .not_null()
+        .default(Expr::current_timestamp()),
+    )
+    .to_string(PostgresQueryBuilder);
+
+  let audit_log_index = Index::create()
+    .if_not_exists()
+    .name("audit_log_user_id_idx")
+    .table(AuditLogIden::Table)
+    .col(AuditLogIden::UserId)
+    .col(AuditLogIden::Id)
+    .to_string(PostgresQueryBuilder);
+
+  vec![audit_log_table, audit_log_index]
+}
+
+pub fn drop_tables() -> Vec<String> {
+  vec![Table::drop()
+    .table(AuditLogIden::Table)
+    .if_exists()
+    .to_string(PostgresQueryBuilder)]
+}
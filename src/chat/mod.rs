@@ -3,6 +3,8 @@
 mod routes;
 pub mod schemas;
 
+pub use routes::{chats_cache_key, messages_cache_key};
+
 use crate::user::auth;
 use axum::{
   middleware::from_fn,
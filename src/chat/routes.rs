@@ -2,41 +2,48 @@
 
 use super::schemas::{Chat, ChatIden, Message, MessageIden};
 use crate::{
+  audit::{schemas::AuditAction, AuditEntry, ClientInfo},
   chat::schemas::Role,
-  db::{cache, postgres},
+  config::config,
+  db::{
+    cache::{self, CacheKind},
+    pin_primary, postgres, postgres_read,
+  },
+  extract::{Json, Path},
+  metrics::ActiveGeneration,
+  ollama::backends,
   result::{Error, Result},
-  state::ollama,
-  user::auth::Auth,
+  usage,
+  user::{
+    auth::Auth,
+    limits::{self, Limits},
+  },
+};
+use axum::Extension;
+use metrics::{counter, histogram};
+use ollama::generation::chat::{
+  request::ChatMessageRequest, ChatMessage, ChatMessageFinalResponseData,
 };
-use axum::{extract::Path, Json};
-use ollama::generation::chat::{request::ChatMessageRequest, ChatMessage};
 use sea_query::{Expr, Order, PostgresQueryBuilder, Query};
 use serde::{Deserialize, Serialize};
-use sqlx::{query, query_as, Row};
-use std::fmt::Display;
-use tracing::instrument;
+use sqlx::{query, query_as, PgPool, Row};
+use std::{fmt::Display, time::Duration};
+use tokio::time::timeout;
+use tracing::{debug, instrument};
 use ts_rs::TS;
-use validator::Validate;
+use validator::{Validate, ValidationError, ValidationErrors};
 
-fn chats_cache_key(pref: impl Display) -> String {
+pub fn chats_cache_key(pref: impl Display) -> String {
   format!("{pref}:get_chats")
 }
 
-fn messages_cache_key(pref: impl Display) -> String {
+pub fn messages_cache_key(pref: impl Display) -> String {
   format!("{pref}:get_messages")
 }
 
 /// get all chats
 #[instrument(name = "chats::get_chats")]
 pub async fn get_chats(Auth(user_id): Auth) -> Result<Json<Vec<Chat>>> {
-  let db = postgres(user_id);
-
-  let redis_key = chats_cache_key(user_id);
-
-  if let Some(cached) = cache::get(&redis_key) {
-    return Ok(Json(cached));
-  }
-
   let chats_query = Query::select()
     .from(ChatIden::Table)
     .columns([
@@ -44,13 +51,19 @@ pub async fn get_chats(Auth(user_id): Auth) -> Result<Json<Vec<Chat>>> {
       ChatIden::Model,
       ChatIden::Title,
       ChatIden::UserId,
+      ChatIden::LastActivityAt,
     ])
     .and_where(Expr::col(ChatIden::UserId).eq(user_id))
+    .order_by(ChatIden::LastActivityAt, Order::Desc)
     .to_string(PostgresQueryBuilder);
 
-  let chats: Vec<Chat> = query_as(&chats_query).fetch_all(db).await?;
+  // the pool is picked after a bump, so a pinned writer reads back from the primary
+  let chats = cache::get_or_load(CacheKind::Chats, &chats_cache_key(user_id), || async {
+    let db = postgres_read(user_id).await?;
 
-  cache::set(&redis_key, &chats, 460);
+    Ok(query_as(&chats_query).fetch_all(db).await?)
+  })
+  .await?;
 
   Ok(Json(chats))
 }
@@ -71,7 +84,7 @@ pub async fn create_chat(
 ) -> Result<Json<Chat>> {
   new_chat.validate()?;
 
-  let db = postgres(user_id);
+  let db = postgres(user_id)?;
 
   let create_chat_query = Query::insert()
     .into_table(ChatIden::Table)
@@ -81,19 +94,21 @@ pub async fn create_chat(
       new_chat.model.clone().into(),
       user_id.into(),
     ])
-    .returning_col(ChatIden::Id)
+    .returning(Query::returning().columns([ChatIden::Id, ChatIden::LastActivityAt]))
     .to_string(PostgresQueryBuilder);
 
-  let chat_id = query(&create_chat_query).fetch_one(db).await?.get(0);
+  let row = query(&create_chat_query).fetch_one(db).await?;
 
   let new_chat = Chat {
-    id: chat_id,
+    id: row.get(0),
     title: new_chat.title,
     model: new_chat.model,
     user_id,
+    last_activity_at: row.get(1),
   };
 
-  cache::invalidate(&chats_cache_key(user_id));
+  pin_primary(user_id).await;
+  cache::bump(&chats_cache_key(user_id)).await;
 
   Ok(Json(new_chat))
 }
@@ -101,7 +116,7 @@ pub async fn create_chat(
 /// edit chat
 #[instrument(name = "chats::edit_chat")]
 pub async fn edit_chat(Auth(user_id): Auth, Json(chat): Json<Chat>) -> Result<()> {
-  let db = postgres(user_id);
+  let db = postgres(user_id)?;
 
   let chat_update_query = Query::update()
     .table(ChatIden::Table)
@@ -119,15 +134,20 @@ pub async fn edit_chat(Auth(user_id): Auth, Json(chat): Json<Chat>) -> Result<()
     return Err(Error::NotFound);
   }
 
-  cache::invalidate(&chats_cache_key(user_id));
+  pin_primary(user_id).await;
+  cache::bump(&chats_cache_key(user_id)).await;
 
   Ok(())
 }
 
 /// delete chat
 #[instrument(name = "chats::delete_chat")]
-pub async fn delete_chat(Auth(user_id): Auth, chat_id: Path<i32>) -> Result<()> {
-  let db = postgres(user_id);
+pub async fn delete_chat(
+  Auth(user_id): Auth,
+  client: ClientInfo,
+  chat_id: Path<i32>,
+) -> Result<()> {
+  let db = postgres(user_id)?;
 
   let delete_chat_query = Query::delete()
     .from_table(ChatIden::Table)
@@ -135,27 +155,23 @@ pub async fn delete_chat(Auth(user_id): Auth, chat_id: Path<i32>) -> Result<()>
     .and_where(Expr::col(ChatIden::UserId).eq(user_id))
     .to_string(PostgresQueryBuilder);
 
-  query(&delete_chat_query).execute(db).await?;
-
-  cache::invalidate(&chats_cache_key(user_id));
-
-  Ok(())
-}
+  let res = query(&delete_chat_query).execute(db).await?;
 
-/// get chat messages
-#[instrument(name = "chats::get_messages")]
-pub async fn get_messages(
-  Auth(user_id): Auth,
-  Path(chat_id): Path<i32>,
-) -> Result<Json<Vec<Message>>> {
-  let redis_key = messages_cache_key(format!("{chat_id}-{user_id}"));
+  pin_primary(user_id).await;
+  cache::bump(&chats_cache_key(user_id)).await;
 
-  if let Some(cached) = cache::get(&redis_key) {
-    return Ok(Json(cached));
+  if res.rows_affected() > 0 {
+    AuditEntry::new(user_id, AuditAction::ChatDeleted)
+      .details(format!("chat {}", chat_id.0))
+      .record(&client)
+      .await;
   }
 
-  let db = postgres(user_id);
+  Ok(())
+}
 
+/// load chat messages from db, oldest first
+async fn load_messages(db: &PgPool, chat_id: i32, user_id: i32) -> Result<Vec<Message>> {
   let get_msgs_query = Query::select()
     .from(MessageIden::Table)
     .inner_join(
@@ -188,7 +204,25 @@ pub async fn get_messages(
     })
     .collect();
 
-  cache::set(&redis_key, &messages, 460);
+  Ok(messages)
+}
+
+/// get chat messages
+#[instrument(name = "chats::get_messages")]
+pub async fn get_messages(
+  Auth(user_id): Auth,
+  Path(chat_id): Path<i32>,
+) -> Result<Json<Vec<Message>>> {
+  let messages = cache::get_or_load(
+    CacheKind::Messages,
+    &messages_cache_key(format!("{chat_id}-{user_id}")),
+    || async {
+      let db = postgres_read(user_id).await?;
+
+      load_messages(db, chat_id, user_id).await
+    },
+  )
+  .await?;
 
   Ok(Json(messages))
 }
@@ -199,14 +233,37 @@ pub struct SendMessageRequest {
   text: String,
 }
 
+/// Record token counts and speed of a finished generation
+fn record_generation_metrics(model: &str, usage: &ChatMessageFinalResponseData) {
+  counter!("ollama_tokens_total", "model" => model.to_string(), "kind" => "prompt")
+    .increment(usage.prompt_eval_count.into());
+  counter!("ollama_tokens_total", "model" => model.to_string(), "kind" => "completion")
+    .increment(usage.eval_count.into());
+
+  if usage.eval_duration > 0 {
+    let secs = Duration::from_nanos(usage.eval_duration).as_secs_f64();
+
+    histogram!("ollama_generation_tokens_per_second", "model" => model.to_string())
+      .record(f64::from(usage.eval_count) / secs);
+  }
+}
+
 /// send a message to a chat. returns ai response
 #[instrument(name = "chats::send_message")]
 pub async fn send_message(
   Auth(user_id): Auth,
+  Extension(limits): Extension<Limits>,
   Path(chat_id): Path<i32>,
   Json(user_msg): Json<SendMessageRequest>,
 ) -> Result<Json<Message>> {
-  let db = postgres(user_id);
+  if user_msg.text.chars().count() > config().ollama.max_message_length {
+    let mut errors = ValidationErrors::new();
+    errors.add("text", ValidationError::new("length"));
+
+    return Err(errors.into());
+  }
+
+  let db = postgres(user_id)?;
 
   // get chat AI model
   let model_query = Query::select()
@@ -216,96 +273,146 @@ pub async fn send_message(
     .and_where(Expr::col(ChatIden::UserId).eq(user_id))
     .to_string(PostgresQueryBuilder);
 
-  let Ok(chat_model) = query(&model_query).fetch_one(db).await?.try_get(0) else {
-    return Err(Error::NotFound); // todo: better errors
-  };
+  let chat_model: String = query(&model_query)
+    .fetch_optional(db)
+    .await?
+    .ok_or(Error::NotFound)?
+    .try_get(0)?;
 
-  // try get messages from cache
   let redis_key = messages_cache_key(format!("{chat_id}-{user_id}"));
 
-  let mut messages = cache::get::<Vec<Message>>(&redis_key)
-    .unwrap_or_default()
-    .into_iter()
-    .map(|msg| {
-      let message = match msg.role {
-        Role::User => ChatMessage::user,
-        Role::Ai => ChatMessage::assistant,
-        Role::System => ChatMessage::system,
-      };
+  let mut messages = cache::get_or_load(CacheKind::Messages, &redis_key, || {
+    load_messages(db, chat_id, user_id)
+  })
+  .await?
+  .into_iter()
+  .map(|msg| {
+    let message = match msg.role {
+      Role::User => ChatMessage::user,
+      Role::Ai => ChatMessage::assistant,
+      Role::System => ChatMessage::system,
+    };
+
+    message(msg.text)
+  })
+  .collect::<Vec<ChatMessage>>();
+
+  limits::check_tokens(user_id, &limits).await?;
+  let generation = limits::start_generation(user_id, &limits).await?;
+
+  // checks ownership again, the chat could be deleted or moved meanwhile. updating the
+  // chat also locks it until the message is inserted
+  let touch_chat_query = Query::update()
+    .table(ChatIden::Table)
+    .value(ChatIden::LastActivityAt, Expr::current_timestamp())
+    .and_where(Expr::col(ChatIden::Id).eq(chat_id))
+    .and_where(Expr::col(ChatIden::UserId).eq(user_id))
+    .to_string(PostgresQueryBuilder);
 
-      message(msg.text)
-    })
-    .collect::<Vec<ChatMessage>>();
-
-  // if cache is empty, fetch messages from db
-  if messages.is_empty() {
-    let get_messages_query = Query::select()
-      .from(MessageIden::Table)
-      .inner_join(
-        ChatIden::Table,
-        Expr::col((MessageIden::Table, MessageIden::ChatId))
-          .equals((ChatIden::Table, ChatIden::Id)),
-      )
-      .columns([
-        (MessageIden::Table, MessageIden::Id),
-        (MessageIden::Table, MessageIden::Text),
-        (MessageIden::Table, MessageIden::Role),
-      ])
-      .and_where(Expr::col(MessageIden::ChatId).eq(chat_id))
-      .and_where(Expr::col((ChatIden::Table, ChatIden::UserId)).eq(user_id))
-      .order_by(MessageIden::Id, Order::Asc)
-      .to_string(PostgresQueryBuilder);
-
-    messages = query(&get_messages_query)
-      .fetch_all(db)
-      .await?
-      .into_iter()
-      .filter_map(|row| {
-        let msg = match Role::from_i16(row.get("role")).ok()? {
-          Role::User => ChatMessage::user,
-          Role::Ai => ChatMessage::assistant,
-          Role::System => ChatMessage::system,
-        };
-
-        Some(msg(row.get("text")))
-      })
-      .collect();
+  let insert_msg_query = |text: String, role: Role| {
+    Query::insert()
+      .into_table(MessageIden::Table)
+      .columns([MessageIden::Text, MessageIden::Role, MessageIden::ChatId])
+      .values_panic([text.into(), role.into(), chat_id.into()])
+      .returning_col(MessageIden::Id)
+      .to_string(PostgresQueryBuilder)
+  };
+
+  // the user message is saved before generating, so it survives timeouts and shutdown
+  let user_msg = user_msg.text;
+  let mut tx = db.begin().await?;
+
+  if query(&touch_chat_query)
+    .execute(&mut *tx)
+    .await?
+    .rows_affected()
+    == 0
+  {
+    return Err(Error::NotFound);
   }
 
+  let user_msg_id: i32 = query(&insert_msg_query(user_msg.clone(), Role::User))
+    .fetch_one(&mut *tx)
+    .await?
+    .get(0);
+
+  tx.commit().await?;
+
+  debug!(user_msg_id, "user message saved");
+
+  pin_primary(user_id).await;
+  cache::bump(&redis_key).await;
+  cache::bump(&chats_cache_key(user_id)).await;
+
   // send chat messages to ollama
-  let user_msg = user_msg.text;
+  messages.push(ChatMessage::user(user_msg));
 
-  ollama()
-    .send_chat_messages_with_history(
-      &mut messages,
-      ChatMessageRequest::new(chat_model, vec![ChatMessage::user(user_msg.clone())]),
-    )
-    .await?;
+  let request = ChatMessageRequest::new(chat_model.clone(), messages);
+  let active = ActiveGeneration::start();
 
-  let ai_res = messages.last().unwrap();
+  let ai_res = timeout(
+    config().ollama.timeout(),
+    backends::with_backend(&chat_model, |ollama| {
+      ollama.send_chat_messages(request.clone())
+    }),
+  )
+  .await
+  .map_err(|_| {
+    counter!("ollama_timeouts_total", "model" => chat_model.clone()).increment(1);
 
-  // insert new messages
-  let insert_msgs_query = Query::insert()
-    .into_table(MessageIden::Table)
-    .columns([MessageIden::Text, MessageIden::Role, MessageIden::ChatId])
-    .values_panic([user_msg.into(), Role::User.into(), chat_id.into()])
-    .values_panic([
-      ai_res.content.clone().into(),
-      Role::Ai.into(),
-      chat_id.into(),
-    ])
-    .to_string(PostgresQueryBuilder);
+    Error::OllamaTimeout
+  })??;
+
+  drop(active);
+  drop(generation);
+
+  let usage = ai_res.final_data;
+
+  if let Some(usage) = &usage {
+    let tokens = u64::from(usage.prompt_eval_count) + u64::from(usage.eval_count);
+    limits::record_tokens(user_id, tokens).await;
+
+    record_generation_metrics(&chat_model, usage);
+  }
+
+  let ai_text = ai_res.message.content;
+
+  // user may have been moved to another shard while generating
+  let db = postgres(user_id)?;
+  let mut tx = db.begin().await?;
+
+  if query(&touch_chat_query)
+    .execute(&mut *tx)
+    .await?
+    .rows_affected()
+    == 0
+  {
+    return Err(Error::NotFound);
+  }
+
+  let ai_msg_id: i32 = query(&insert_msg_query(ai_text.clone(), Role::Ai))
+    .fetch_one(&mut *tx)
+    .await?
+    .get(0);
+
+  if let Some(usage) = &usage {
+    usage::record(&mut tx, user_id, ai_msg_id, &chat_model, usage).await?;
+  }
+
+  tx.commit().await?;
 
-  query(&insert_msgs_query).execute(db).await?;
+  debug!(ai_msg_id, "ai message saved");
 
   let ai_res = Message {
-    id: messages.len() as i32,
-    text: ai_res.content.clone(),
+    id: ai_msg_id,
+    text: ai_text,
     role: Role::Ai,
     chat_id,
   };
 
-  cache::invalidate(&redis_key);
+  pin_primary(user_id).await;
+  cache::bump(&redis_key).await;
+  cache::bump(&chats_cache_key(user_id)).await;
 
   Ok(Json(ai_res))
 }
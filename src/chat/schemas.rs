@@ -4,9 +4,10 @@ use crate::{
   result::{Error, Result},
   user::schemas::UserIden,
 };
-use sea_query::{enum_def, ColumnDef, ForeignKey, PostgresQueryBuilder, Table, Value};
+use chrono::{DateTime, Utc};
+use sea_query::{enum_def, ColumnDef, Expr, ForeignKey, PostgresQueryBuilder, Table, Value};
 use serde::{Deserialize, Serialize};
-use sqlx::{FromRow, PgPool};
+use sqlx::FromRow;
 use ts_rs::TS;
 
 #[derive(TS, Debug, Deserialize, Serialize)]
@@ -45,6 +46,10 @@ pub struct Chat {
   pub title: String,
   pub model: String,
   pub user_id: i32,
+  /// Time of the last message. Set by the server
+  #[serde(default)]
+  #[ts(optional)]
+  pub last_activity_at: Option<DateTime<Utc>>,
 }
 
 #[enum_def]
@@ -59,7 +64,8 @@ pub struct Message {
   pub chat_id: i32,
 }
 
-pub async fn create_tables(pool: &PgPool) -> Result {
+/// Statements of the `create_chat_tables` migration. See [crate::db::migrations]
+pub fn create_tables() -> Vec<String> {
   let chat_table = Table::create()
     .table(ChatIden::Table)
     .if_not_exists()
@@ -106,8 +112,38 @@ pub async fn create_tables(pool: &PgPool) -> Result {
     )
     .to_string(PostgresQueryBuilder);
 
-  sqlx::query(&chat_table).execute(pool).await?;
-  sqlx::query(&message_table).execute(pool).await?;
+  vec![chat_table, message_table]
+}
+
+/// Statements of the `add_chat_last_activity` migration
+pub fn add_last_activity() -> Vec<String> {
+  vec![Table::alter()
+    .table(ChatIden::Table)
+    .add_column_if_not_exists(
+      ColumnDef::new(ChatIden::LastActivityAt)
+        .timestamp_with_time_zone()
+        .not_null()
+        .default(Expr::current_timestamp()),
+    )
+    .to_string(PostgresQueryBuilder)]
+}
+
+pub fn drop_last_activity() -> Vec<String> {
+  vec![Table::alter()
+    .table(ChatIden::Table)
+    .drop_column(ChatIden::LastActivityAt)
+    .to_string(PostgresQueryBuilder)]
+}
 
-  Ok(())
+pub fn drop_tables() -> Vec<String> {
+  vec![
+    Table::drop()
+      .table(MessageIden::Table)
+      .if_exists()
+      .to_string(PostgresQueryBuilder),
+    Table::drop()
+      .table(ChatIden::Table)
+      .if_exists()
+      .to_string(PostgresQueryBuilder),
+  ]
 }
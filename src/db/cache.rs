@@ -1,61 +1,280 @@
-//! Redis cache utils
-
-use super::redis;
-use redis::Commands;
-use serde::{de::DeserializeOwned, Serialize};
-use serde_json as json;
-use tracing::{debug, error, instrument};
-
-#[instrument]
-pub fn get<T: DeserializeOwned>(key: &str) -> Option<T> {
-  let cached = redis().lock().get::<_, String>(&key);
-
-  let Ok(cached) = cached else {
-    debug!("cache miss");
-    return None;
-  };
-
-  debug!("cache hit");
-
-  match json::from_str(&cached) {
-    Ok(v) => Some(v),
-    Err(e) => {
-      error!("{e}");
-
-      None
-    }
-  }
-}
-
-#[instrument(skip(value))]
-pub fn set(key: &str, value: impl Serialize, ex: u64) {
-  let value = match json::to_string(&value) {
-    Ok(v) => v,
-    Err(e) => {
-      error!("{e}");
-
-      return;
-    }
-  };
-
-  let res = redis().lock().set_ex::<_, _, ()>(key, value, ex);
-
-  // set chache errors should not impact the main flow
-  if let Err(e) = res {
-    error!("{e}");
-  }
-}
-
-/// invalidate a cache key without blocking the main flow
-#[instrument]
-pub fn invalidate(key: &str) {
-  let key = key.to_string();
-
-  tokio::spawn(async move {
-    let res = redis().lock().del::<_, ()>(key);
-
-    if let Err(e) = res {
-      error!("{e}");
-    }
-  });
-}
+//! Redis cache utils
+//!
+//! - Plain keys: [get], [set], [invalidate]
+//! - Cached query results: [get_or_load] and [bump]. Values are stored under generation
+//!   tagged keys. Bumping the generation makes all readers miss, even those which are
+//!   about to write a value loaded before the bump, since they write to the old
+//!   generation.
+//!
+//! Writers pin the user to the primary before bumping, see [super::pin_primary], so
+//! loaders may read through [super::postgres_read] as long as they pick the pool inside
+//! the loader, after the bump. Values written without a pin, e.g. by admins, must be
+//! loaded from the primary, since a lagging replica would cache stale rows in the new
+//! generation.
+//!
+//! Cache is bypassed while Redis is down, see [super::health]. Invalidations are
+//! deferred until it is back.
+
+use super::{health, redis};
+use crate::{config::config, result::Result};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use metrics::counter;
+use parking_lot::Mutex;
+use redis::{aio::ConnectionManager, AsyncCommands};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json as json;
+use std::{
+  collections::{HashMap, HashSet},
+  future::Future,
+  sync::{Arc, LazyLock, Weak},
+};
+use tracing::{debug, error, instrument};
+
+/// TTLs are randomly shortened by up to this fraction, so entries cached together don't
+/// expire together
+const TTL_JITTER: f64 = 0.1;
+
+/// Generation counters must outlive values, otherwise a reset counter could reveal a
+/// stale value of the same generation
+const GENERATION_TTL: u64 = 24 * 60 * 60;
+
+/// Types of cached query results. Each has its own TTL
+#[derive(Debug, Clone, Copy)]
+pub enum CacheKind {
+  Chats,
+  Messages,
+}
+
+impl CacheKind {
+  /// TTL in seconds, see [crate::config::CacheConfig]
+  fn ttl(self) -> u64 {
+    let ttls = &config().cache;
+
+    match self {
+      CacheKind::Chats => ttls.ttl_chats,
+      CacheKind::Messages => ttls.ttl_messages,
+    }
+  }
+}
+
+fn jittered(ttl: u64) -> u64 {
+  let max_jitter = (ttl as f64 * TTL_JITTER) as u64;
+
+  if max_jitter == 0 {
+    return ttl;
+  }
+
+  ttl - OsRng.next_u64() % (max_jitter + 1)
+}
+
+/// Invalidations which failed while Redis was down
+#[derive(Debug, PartialEq, Eq, Hash)]
+enum Invalidation {
+  Delete(String),
+  Bump(String),
+}
+
+static PENDING: LazyLock<Mutex<HashSet<Invalidation>>> = LazyLock::new(Mutex::default);
+
+impl Invalidation {
+  async fn apply(&self, redis: &mut ConnectionManager) -> redis::RedisResult<()> {
+    match self {
+      Invalidation::Delete(key) => redis.del(key).await,
+      Invalidation::Bump(key) => {
+        let generation_key = generation_key(key);
+
+        redis::pipe()
+          .atomic()
+          .incr(&generation_key, 1)
+          .ignore()
+          .expire(&generation_key, GENERATION_TTL as i64)
+          .ignore()
+          .query_async(redis)
+          .await
+      }
+    }
+  }
+
+  /// Apply now or defer until Redis is back
+  async fn run(self) {
+    let Some(mut redis) = redis() else {
+      PENDING.lock().insert(self);
+      return;
+    };
+
+    if let Err(e) = self.apply(&mut redis).await {
+      error!("{e}");
+      health::redis_failed();
+
+      PENDING.lock().insert(self);
+    }
+  }
+}
+
+/// Number of invalidations deferred while Redis is down
+pub fn pending() -> usize {
+  PENDING.lock().len()
+}
+
+/// Apply invalidations deferred while Redis was down
+pub async fn flush_pending() {
+  let Some(mut redis) = redis() else {
+    return;
+  };
+
+  let pending: Vec<_> = PENDING.lock().drain().collect();
+
+  for (i, invalidation) in pending.iter().enumerate() {
+    if let Err(e) = invalidation.apply(&mut redis).await {
+      error!("Failed to flush pending invalidations: {e}");
+
+      PENDING.lock().extend(pending.into_iter().skip(i));
+      return;
+    }
+  }
+}
+
+#[instrument]
+pub async fn get<T: DeserializeOwned>(key: &str) -> Option<T> {
+  let mut redis = redis()?;
+
+  let cached = match redis.get::<_, Option<String>>(key).await {
+    Ok(Some(cached)) => cached,
+    Ok(None) => {
+      debug!("cache miss");
+      counter!("cache_lookups_total", "result" => "miss").increment(1);
+
+      return None;
+    }
+    Err(e) => {
+      error!("{e}");
+      health::redis_failed();
+      counter!("cache_lookups_total", "result" => "error").increment(1);
+
+      return None;
+    }
+  };
+
+  debug!("cache hit");
+  counter!("cache_lookups_total", "result" => "hit").increment(1);
+
+  match json::from_str(&cached) {
+    Ok(v) => Some(v),
+    Err(e) => {
+      error!("{e}");
+
+      None
+    }
+  }
+}
+
+#[instrument(skip(value))]
+pub async fn set(key: &str, value: impl Serialize, ex: u64) {
+  let Some(mut redis) = redis() else {
+    return;
+  };
+
+  let value = match json::to_string(&value) {
+    Ok(v) => v,
+    Err(e) => {
+      error!("{e}");
+
+      return;
+    }
+  };
+
+  let res = redis.set_ex::<_, _, ()>(key, value, ex).await;
+
+  // set chache errors should not impact the main flow
+  if let Err(e) = res {
+    error!("{e}");
+    health::redis_failed();
+  }
+}
+
+/// delete a cache key. Deferred until Redis is back if it is down
+#[instrument]
+pub async fn invalidate(key: &str) {
+  Invalidation::Delete(key.to_string()).run().await;
+}
+
+fn generation_key(key: &str) -> String {
+  format!("{key}:gen")
+}
+
+/// Key of the current generation of the value, `None` if Redis is down
+async fn generation_tagged_key(key: &str) -> Option<String> {
+  let mut redis = redis()?;
+
+  match redis.get::<_, Option<u64>>(generation_key(key)).await {
+    Ok(generation) => Some(format!("{key}:v{}", generation.unwrap_or_default())),
+    Err(e) => {
+      error!("{e}");
+      health::redis_failed();
+
+      None
+    }
+  }
+}
+
+/// Requests loading the same key wait for each other instead of all hitting the DB
+static IN_FLIGHT: LazyLock<Mutex<HashMap<String, Weak<tokio::sync::Mutex<()>>>>> =
+  LazyLock::new(Mutex::default);
+
+fn flight(key: &str) -> Arc<tokio::sync::Mutex<()>> {
+  let mut in_flight = IN_FLIGHT.lock();
+
+  if let Some(flight) = in_flight.get(key).and_then(Weak::upgrade) {
+    return flight;
+  }
+
+  in_flight.retain(|_, flight| flight.strong_count() > 0);
+
+  let flight = Arc::new(tokio::sync::Mutex::new(()));
+  in_flight.insert(key.to_string(), Arc::downgrade(&flight));
+
+  flight
+}
+
+/// Get cached value or load it. Concurrent misses of the same key in
+/// this process are loaded once
+#[instrument(skip(load))]
+pub async fn get_or_load<T, F, Fut>(kind: CacheKind, key: &str, load: F) -> Result<T>
+where
+  T: Serialize + DeserializeOwned,
+  F: FnOnce() -> Fut,
+  Fut: Future<Output = Result<T>>,
+{
+  let Some(tagged_key) = generation_tagged_key(key).await else {
+    return load().await;
+  };
+
+  if let Some(cached) = get(&tagged_key).await {
+    return Ok(cached);
+  }
+
+  let flight = flight(key);
+  let _loading = flight.lock().await;
+
+  // loaded by a concurrent request while we were waiting
+  let Some(tagged_key) = generation_tagged_key(key).await else {
+    return load().await;
+  };
+
+  if let Some(cached) = get(&tagged_key).await {
+    return Ok(cached);
+  }
+
+  let value = load().await?;
+
+  // if bumped while loading, this lands in the old generation and is never read
+  set(&tagged_key, &value, jittered(kind.ttl())).await;
+
+  Ok(value)
+}
+
+/// Invalidate a value cached with [get_or_load]
+#[instrument]
+pub async fn bump(key: &str) {
+  Invalidation::Bump(key.to_string()).run().await;
+}
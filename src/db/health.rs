@@ -0,0 +1,163 @@
+//! Dependency health tracking
+//!
+//! Shards and Redis are probed in the background. Requests touching an unhealthy shard
+//! fail fast with 503 instead of waiting for connection timeouts, so only users stored on
+//! that shard are affected. Unhealthy read replicas are skipped. The cache is bypassed
+//! while Redis is down.
+//!
+//! Shards are also unavailable until they are migrated, see [super::run_migrations].
+
+use super::{cache, get, redis_config, shards};
+use redis::{aio::ConnectionManager, AsyncCommands};
+use sqlx::{query, PgPool};
+use std::{
+  sync::atomic::{AtomicBool, Ordering},
+  time::Duration,
+};
+use tokio::time::timeout;
+use tracing::{info, warn};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(2);
+const CHECK_TIMEOUT: Duration = Duration::from_secs(1);
+
+const TIMED_OUT: &str = "timed out";
+
+pub(super) struct Health {
+  shards: Vec<AtomicBool>,
+  /// Whether the shard is at the latest schema version
+  migrated: Vec<AtomicBool>,
+  /// Read replicas of every shard
+  replicas: Vec<Vec<AtomicBool>>,
+  redis: AtomicBool,
+}
+
+impl Health {
+  pub(super) fn new(replicas: &[Vec<PgPool>]) -> Self {
+    let up = |n: usize| (0..n).map(|_| AtomicBool::new(true)).collect();
+
+    Self {
+      shards: up(replicas.len()),
+      migrated: (0..replicas.len())
+        .map(|_| AtomicBool::new(false))
+        .collect(),
+      replicas: replicas.iter().map(|replicas| up(replicas.len())).collect(),
+      redis: AtomicBool::new(false),
+    }
+  }
+}
+
+fn health() -> &'static Health {
+  &get().health
+}
+
+/// Whether the shard is migrated and responded to the last probe
+pub fn shard_up(index: usize) -> bool {
+  shard_migrated(index) && shard_reachable(index)
+}
+
+/// Whether the shard responded to the last probe
+pub fn shard_reachable(index: usize) -> bool {
+  health().shards[index].load(Ordering::Relaxed)
+}
+
+pub fn shard_migrated(index: usize) -> bool {
+  health().migrated[index].load(Ordering::Relaxed)
+}
+
+pub(super) fn set_migrated(index: usize) {
+  health().migrated[index].store(true, Ordering::Relaxed);
+}
+
+pub fn replica_up(shard: usize, replica: usize) -> bool {
+  health().replicas[shard][replica].load(Ordering::Relaxed)
+}
+
+pub fn redis_up() -> bool {
+  health().redis.load(Ordering::Relaxed)
+}
+
+/// Stop using Redis until the next successful probe
+pub fn redis_failed() {
+  if health().redis.swap(false, Ordering::Relaxed) {
+    warn!("Redis is down, bypassing cache");
+  }
+}
+
+fn set_status(status: &AtomicBool, up: bool, name: &str) {
+  match (status.swap(up, Ordering::Relaxed), up) {
+    (false, true) => info!("{name} is up"),
+    (true, false) => warn!("{name} is down"),
+    _ => {}
+  }
+}
+
+/// Ping Redis, connecting first if it was down on startup
+pub async fn probe_redis() -> Result<(), String> {
+  let mut connection = match get().redis.get() {
+    Some(connection) => connection.clone(),
+    None => {
+      // connection could not be established on startup
+      let connection = timeout(
+        CHECK_TIMEOUT * 2,
+        ConnectionManager::new_with_config(get().redis_client.clone(), redis_config()),
+      )
+      .await
+      .map_err(|_| TIMED_OUT.to_string())?
+      .map_err(|e| e.to_string())?;
+
+      get().redis.get_or_init(|| connection).clone()
+    }
+  };
+
+  match timeout(CHECK_TIMEOUT, connection.ping::<String>()).await {
+    Ok(Ok(_)) => Ok(()),
+    Ok(Err(e)) => Err(e.to_string()),
+    Err(_) => Err(TIMED_OUT.to_string()),
+  }
+}
+
+/// Run `SELECT 1` on the shard or replica
+pub async fn probe_postgres(db: &PgPool) -> Result<(), String> {
+  match timeout(CHECK_TIMEOUT, query("SELECT 1").execute(db)).await {
+    Ok(Ok(_)) => Ok(()),
+    Ok(Err(e)) => Err(e.to_string()),
+    Err(_) => Err(TIMED_OUT.to_string()),
+  }
+}
+
+/// Probe all dependencies once
+pub(super) async fn check() {
+  for (i, db) in shards().iter().enumerate() {
+    set_status(
+      &health().shards[i],
+      probe_postgres(db).await.is_ok(),
+      &format!("Shard {}", i + 1),
+    );
+
+    for (j, replica) in get().replicas[i].iter().enumerate() {
+      set_status(
+        &health().replicas[i][j],
+        probe_postgres(replica).await.is_ok(),
+        &format!("Replica {} of shard {}", j + 1, i + 1),
+      );
+    }
+  }
+
+  let redis_up = probe_redis().await.is_ok();
+  set_status(&health().redis, redis_up, "Redis");
+
+  if redis_up {
+    cache::flush_pending().await;
+  }
+}
+
+/// Keep probing dependencies in the background
+pub(super) fn spawn_monitor() {
+  tokio::spawn(async {
+    loop {
+      tokio::time::sleep(CHECK_INTERVAL).await;
+
+      check().await;
+    }
+  });
+}
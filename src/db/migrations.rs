@@ -0,0 +1,468 @@
+//! Versioned schema migrations
+//!
+//! Every shard records applied versions in the `schema_migration` table. Migrations are
+//! applied in order, each shard in a single transaction guarded by an advisory lock, so
+//! concurrently starting instances don't apply the same migration twice.
+//!
+//! **Never edit a released migration**, add a new one instead.
+//!
+//! Besides schema statements a migration may carry a data migration, e.g. seeding
+//! cluster wide state on the metadata shard from the rows of each shard. Shards are
+//! migrated in order, so the metadata shard is always migrated first.
+//!
+//! Usage:
+//! - `robo migrate up [--dry-run]` - apply pending migrations
+//! - `robo migrate down <version> [--dry-run]` - revert migrations newer than `version`
+//! - `robo migrate status` - print versions of all shards
+//!
+//! The server applies pending migrations on startup unless `MIGRATE_ON_STARTUP=false`.
+//! It refuses to start when the metadata shard is not at the latest version, other
+//! shards stay unavailable until they are, see [super::run_migrations].
+
+use super::{
+  create_user_id_sequence, drop_user_id_sequence, shards, sync_user_id_sequence, METADATA_SHARD,
+};
+use crate::{
+  audit::schemas as audit,
+  chat::schemas as chat,
+  config::config,
+  db::reshard,
+  result::{Error, Result},
+  usage::schemas as usage,
+  user::{directory, schemas as user},
+};
+use futures::future::BoxFuture;
+use sea_query::{ColumnDef, Expr, Iden, Order, PostgresQueryBuilder, Query, Table};
+use sqlx::{query, query_scalar, PgConnection, PgPool};
+use std::fmt::{self, Display};
+use tracing::{info, instrument};
+
+/// Key of the advisory lock held while migrating a shard
+const MIGRATION_LOCK: i64 = 0x726f626f; // "robo"
+
+/// Data migration of a shard, gets the shard index. Runs in the migration transaction
+/// after `up` statements. May also write to the [super::metadata] shard, those writes
+/// must be idempotent since the transaction may still fail afterwards
+type DataMigration = for<'c> fn(&'c mut PgConnection, usize) -> BoxFuture<'c, sqlx::Result<()>>;
+
+struct Migration {
+  version: i32,
+  name: &'static str,
+  /// Applied only to the [super::metadata] shard, but recorded on all shards to keep
+  /// versions equal
+  metadata_only: bool,
+  up: fn() -> Vec<String>,
+  down: fn() -> Vec<String>,
+  /// Not reverted by `down`
+  data: Option<DataMigration>,
+}
+
+/// Step of migrating a shard, see [migrate_shard]
+enum Step {
+  Statement(String),
+  Data(&'static Migration),
+}
+
+/// Printed by dry runs
+impl Display for Step {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Step::Statement(statement) => write!(f, "{statement};"),
+      Step::Data(migration) => write!(
+        f,
+        "-- data migration {} {}",
+        migration.version, migration.name
+      ),
+    }
+  }
+}
+
+/// All migrations in order. Versions must be sequential starting from 1
+const MIGRATIONS: &[Migration] = &[
+  Migration {
+    version: 1,
+    name: "create_user_tables",
+    metadata_only: false,
+    up: user::create_tables,
+    down: user::drop_tables,
+    data: None,
+  },
+  Migration {
+    version: 2,
+    name: "create_chat_tables",
+    metadata_only: false,
+    up: chat::create_tables,
+    down: chat::drop_tables,
+    data: None,
+  },
+  Migration {
+    version: 3,
+    name: "create_audit_tables",
+    metadata_only: false,
+    up: audit::create_tables,
+    down: audit::drop_tables,
+    data: None,
+  },
+  Migration {
+    version: 4,
+    name: "create_metadata_tables",
+    metadata_only: true,
+    up: || {
+      [
+        reshard::create_tables(),
+        directory::create_tables(),
+        create_user_id_sequence(),
+      ]
+      .concat()
+    },
+    down: || {
+      [
+        drop_user_id_sequence(),
+        directory::drop_tables(),
+        reshard::drop_tables(),
+      ]
+      .concat()
+    },
+    data: None,
+  },
+  Migration {
+    version: 5,
+    name: "add_chat_last_activity",
+    metadata_only: false,
+    up: chat::add_last_activity,
+    down: chat::drop_last_activity,
+    data: None,
+  },
+  Migration {
+    version: 6,
+    name: "add_user_limits",
+    metadata_only: false,
+    up: user::add_limits,
+    down: user::drop_limits,
+    data: None,
+  },
+  Migration {
+    version: 7,
+    name: "create_usage_tables",
+    metadata_only: false,
+    up: usage::create_tables,
+    down: usage::drop_tables,
+    data: None,
+  },
+  Migration {
+    version: 8,
+    name: "add_totp_last_step",
+    metadata_only: false,
+    up: user::add_totp_last_step,
+    down: user::drop_totp_last_step,
+    data: None,
+  },
+  Migration {
+    version: 9,
+    name: "add_tokens_revoked_at",
+    metadata_only: false,
+    up: user::add_tokens_revoked_at,
+    down: user::drop_tokens_revoked_at,
+    data: None,
+  },
+  Migration {
+    version: 10,
+    name: "sync_user_id_sequence",
+    metadata_only: false,
+    up: Vec::new,
+    down: Vec::new,
+    data: Some(sync_user_id_sequence),
+  },
+  Migration {
+    version: 11,
+    name: "align_id_sequences",
+    metadata_only: false,
+    up: Vec::new,
+    down: Vec::new,
+    data: Some(reshard::align_id_sequences),
+  },
+  Migration {
+    version: 12,
+    name: "backfill_email_directory",
+    metadata_only: false,
+    up: Vec::new,
+    down: Vec::new,
+    data: Some(directory::backfill),
+  },
+  Migration {
+    version: 13,
+    name: "lowercase_directory_emails",
+    metadata_only: true,
+    up: Vec::new,
+    down: Vec::new,
+    data: Some(directory::lowercase_emails),
+  },
+];
+
+/// Statements creating the latest schema of a user shard, without metadata tables
+#[cfg(test)]
+pub(super) fn shard_schema() -> Vec<String> {
+  MIGRATIONS
+    .iter()
+    .filter(|m| !m.metadata_only)
+    .flat_map(|m| (m.up)())
+    .collect()
+}
+
+/// Version the app expects all shards to be at
+pub fn latest_version() -> i32 {
+  MIGRATIONS.last().map_or(0, |m| m.version)
+}
+
+#[derive(Iden)]
+enum SchemaMigrationIden {
+  #[iden = "schema_migration"]
+  Table,
+  Version,
+  Name,
+  AppliedAt,
+}
+
+async fn create_migration_table(conn: &mut PgConnection) -> Result {
+  let schema_migration_table = Table::create()
+    .table(SchemaMigrationIden::Table)
+    .if_not_exists()
+    .col(
+      ColumnDef::new(SchemaMigrationIden::Version)
+        .integer()
+        .not_null()
+        .primary_key(),
+    )
+    .col(
+      ColumnDef::new(SchemaMigrationIden::Name)
+        .string()
+        .not_null(),
+    )
+    .col(
+      ColumnDef::new(SchemaMigrationIden::AppliedAt)
+        .timestamp_with_time_zone()
+        .not_null()
+        .default(Expr::current_timestamp()),
+    )
+    .to_string(PostgresQueryBuilder);
+
+  query(&schema_migration_table).execute(conn).await?;
+
+  Ok(())
+}
+
+async fn current_version(conn: &mut PgConnection) -> Result<i32> {
+  let version_query = Query::select()
+    .expr(Expr::col(SchemaMigrationIden::Version).max())
+    .from(SchemaMigrationIden::Table)
+    .to_string(PostgresQueryBuilder);
+
+  let version: Option<i32> = query_scalar(&version_query).fetch_one(conn).await?;
+
+  Ok(version.unwrap_or_default())
+}
+
+async fn shard_version(db: &PgPool) -> Result<i32> {
+  let mut conn = db.acquire().await?;
+
+  create_migration_table(&mut conn).await?;
+
+  current_version(&mut conn).await
+}
+
+/// Migrate a shard to `target` version. Returns executed steps
+async fn migrate_shard(index: usize, target: i32, dry_run: bool) -> Result<Vec<Step>> {
+  let db = &shards()[index];
+  let is_metadata = index == METADATA_SHARD;
+
+  let mut tx = db.begin().await?;
+
+  query("SELECT pg_advisory_xact_lock($1)")
+    .bind(MIGRATION_LOCK)
+    .execute(&mut *tx)
+    .await?;
+
+  create_migration_table(&mut tx).await?;
+
+  // read after locking, another instance may have just migrated
+  let current = current_version(&mut tx).await?;
+
+  // migrated by a newer app version. its migrations are unknown, so can't be reverted
+  if current > latest_version() {
+    return Err(Error::Migration(format!(
+      "shard {} is at version {current}, newer than {}",
+      index + 1,
+      latest_version()
+    )));
+  }
+
+  let mut steps = vec![];
+
+  if current < target {
+    for migration in MIGRATIONS
+      .iter()
+      .filter(|m| m.version > current && m.version <= target)
+    {
+      if !migration.metadata_only || is_metadata {
+        steps.extend((migration.up)().into_iter().map(Step::Statement));
+
+        if migration.data.is_some() {
+          steps.push(Step::Data(migration));
+        }
+      }
+
+      steps.push(Step::Statement(
+        Query::insert()
+          .into_table(SchemaMigrationIden::Table)
+          .columns([SchemaMigrationIden::Version, SchemaMigrationIden::Name])
+          .values_panic([migration.version.into(), migration.name.into()])
+          .to_string(PostgresQueryBuilder),
+      ));
+    }
+  } else {
+    for migration in MIGRATIONS
+      .iter()
+      .rev()
+      .filter(|m| m.version > target && m.version <= current)
+    {
+      if !migration.metadata_only || is_metadata {
+        steps.extend((migration.down)().into_iter().map(Step::Statement));
+      }
+
+      steps.push(Step::Statement(
+        Query::delete()
+          .from_table(SchemaMigrationIden::Table)
+          .and_where(Expr::col(SchemaMigrationIden::Version).eq(migration.version))
+          .to_string(PostgresQueryBuilder),
+      ));
+    }
+  }
+
+  if steps.is_empty() {
+    return Ok(steps);
+  }
+
+  info!(
+    "Migrating shard {} from version {current} to {target}",
+    index + 1
+  );
+
+  if dry_run {
+    tx.rollback().await?;
+
+    return Ok(steps);
+  }
+
+  for step in &steps {
+    match step {
+      Step::Statement(statement) => {
+        query(statement).execute(&mut *tx).await?;
+      }
+      Step::Data(migration) => {
+        if let Some(data) = migration.data {
+          data(&mut tx, index).await?;
+        }
+      }
+    }
+  }
+
+  tx.commit().await?;
+
+  Ok(steps)
+}
+
+/// Migrate all shards to `target` version one by one
+#[instrument]
+pub async fn migrate(target: i32, dry_run: bool) -> Result {
+  if !(0..=latest_version()).contains(&target) {
+    return Err(Error::Migration(format!("unknown version {target}")));
+  }
+
+  for index in 0..shards().len() {
+    let steps = migrate_shard(index, target, dry_run).await?;
+
+    if dry_run {
+      for step in steps {
+        println!("-- shard {}\n{step}", index + 1);
+      }
+    }
+  }
+
+  Ok(())
+}
+
+/// Fails unless the shard is at the latest version
+pub(super) async fn check_version(index: usize) -> Result {
+  let version = shard_version(&shards()[index]).await?;
+
+  if version != latest_version() {
+    return Err(Error::Migration(format!(
+      "shard {} is at version {version}, expected {}",
+      index + 1,
+      latest_version()
+    )));
+  }
+
+  Ok(())
+}
+
+/// Apply pending migrations to the shard unless disabled by
+/// [crate::config::PostgresConfig::migrate_on_startup], then check its version
+pub async fn migrate_shard_on_startup(index: usize) -> Result {
+  if config().postgres.migrate_on_startup {
+    migrate_shard(index, latest_version(), false).await?;
+  }
+
+  check_version(index).await
+}
+
+async fn print_status() -> Result {
+  let versions_query = Query::select()
+    .columns([SchemaMigrationIden::Version, SchemaMigrationIden::Name])
+    .from(SchemaMigrationIden::Table)
+    .order_by(SchemaMigrationIden::Version, Order::Asc)
+    .to_string(PostgresQueryBuilder);
+
+  for (i, db) in shards().iter().enumerate() {
+    let version = shard_version(db).await?;
+
+    println!("shard {}: version {version}", i + 1);
+
+    let applied: Vec<(i32, String)> = sqlx::query_as(&versions_query).fetch_all(db).await?;
+
+    for (version, name) in applied {
+      println!("  applied {version} {name}");
+    }
+
+    for migration in MIGRATIONS.iter().filter(|m| m.version > version) {
+      println!("  pending {} {}", migration.version, migration.name);
+    }
+  }
+
+  Ok(())
+}
+
+/// Run `migrate` CLI command. `args` are the arguments after `migrate`
+pub async fn cli(args: &[String]) -> Result {
+  let dry_run = args.iter().any(|arg| arg == "--dry-run");
+  let args: Vec<&str> = args
+    .iter()
+    .map(String::as_str)
+    .filter(|arg| *arg != "--dry-run")
+    .collect();
+
+  match args.as_slice() {
+    ["up"] => migrate(latest_version(), dry_run).await,
+    ["down", version] => {
+      let version = version
+        .parse()
+        .map_err(|_| Error::Migration(format!("invalid version {version}")))?;
+
+      migrate(version, dry_run).await
+    }
+    ["status"] => print_status().await,
+    _ => Err(Error::Migration(
+      "usage: migrate up|down <version>|status [--dry-run]".into(),
+    )),
+  }
+}
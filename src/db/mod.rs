@@ -1,132 +1,482 @@
 //! DB module
 //!
-//! - App DB is split into two shards based on user id parity.
+//! - App DB is split into N shards based on user id. See [shard_index]
 //! - Also we use Redis for caching.
+//! - Unavailable shards and Redis are tracked by [health]
+//! - The first shard is the [metadata] shard. It stores cluster wide state, e.g. the
+//!   email directory and the user id sequence, so it is a hard dependency: the server
+//!   doesn't start without it and logins and registrations fail while it is down. Other
+//!   shards may be down, only their users are affected.
 
 pub mod cache;
+pub mod health;
+pub mod migrations;
+pub mod reshard;
 
 use crate::{
-  chat::schemas::create_tables as create_chat_tables, result::Result,
-  user::schemas::create_tables as create_user_tables,
+  config::config,
+  result::{Error, Result},
 };
-use parking_lot::Mutex;
-use redis::Client as Redis;
-use sqlx::{query, PgPool, Row};
+use futures::future::{join_all, try_join_all, BoxFuture};
+use health::{replica_up, shard_up, Health};
+use metrics::gauge;
+use parking_lot::RwLock;
+use redis::{
+  aio::{ConnectionManager, ConnectionManagerConfig},
+  Client as Redis,
+};
+use sqlx::{postgres::PgPoolOptions, query, query_scalar, PgConnection, PgPool};
 use std::{
-  env::var,
+  collections::HashMap,
+  future::Future,
   sync::{
-    atomic::{AtomicI32, Ordering},
+    atomic::{AtomicUsize, Ordering},
     OnceLock,
   },
+  time::Duration,
 };
-use tracing::{info, instrument};
+use tokio::time::timeout;
+use tracing::{info, instrument, warn};
+
+/// Redis commands fail after this time instead of blocking requests
+const REDIS_RESPONSE_TIMEOUT: Duration = Duration::from_secs(1);
+const REDIS_CONNECTION_TIMEOUT: Duration = Duration::from_secs(2);
+/// Reconnect attempts with exponential backoff before a command fails
+const REDIS_RECONNECT_RETRIES: usize = 5;
+
+/// Reads of a user go to the primary for this long after their write, so they see it
+/// even if replicas lag behind
+const PRIMARY_PIN_WINDOW: Duration = Duration::from_secs(5);
+
+/// Queries fail after waiting this long for a connection of an unreachable shard
+const PG_ACQUIRE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Connections still in use after this long are dropped on shutdown
+const PG_CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Startup retries while dependencies are unreachable, e.g. still starting up
+const STARTUP_RETRIES: u32 = 8;
+const STARTUP_RETRY_DELAY: Duration = Duration::from_secs(1);
+const STARTUP_MAX_RETRY_DELAY: Duration = Duration::from_secs(16);
+
+/// How often migrations of shards which were unavailable on startup are retried
+const MIGRATION_RETRY_INTERVAL: Duration = Duration::from_secs(30);
 
 pub struct DBState {
-  /// Redis connection
-  redis: Mutex<Redis>,
+  redis_client: Redis,
+
+  /// Multiplexed Redis connection. Reconnects automatically. Missing if Redis was down
+  /// on startup, until [health] monitor connects
+  redis: OnceLock<ConnectionManager>,
 
-  /// Postgres shard for odd user ids
-  shard1: PgPool,
-  /// Postgres shard for even user ids
-  shard2: PgPool,
+  /// Postgres shards. User is stored on the shard returned by [shard_index]
+  shards: Vec<PgPool>,
 
-  /// Max user id among all shards. Used to decide where to put new users
-  max_uid: AtomicI32,
+  /// Optional read replicas of every shard. See [postgres_read]
+  replicas: Vec<Vec<PgPool>>,
+  /// Round-robin counter for picking replicas
+  next_replica: AtomicUsize,
+
+  /// Shard indexes of users moved away from their default shard. See [reshard]
+  overrides: RwLock<HashMap<i32, usize>>,
+
+  health: Health,
 }
 
 static DB_STATE: OnceLock<DBState> = OnceLock::new();
 
-/// Init db connections. Requires the following env vars:
-/// - `REDIS_URL`
-/// - `PG_SHARD1`
-/// - `PG_SHARD2`
+fn connect_lazy(url: &str) -> PgPool {
+  PgPoolOptions::new()
+    .acquire_timeout(PG_ACQUIRE_TIMEOUT)
+    .connect_lazy(url)
+    .expect("Invalid Postgres url")
+}
+
+/// Init db connections from [crate::config::PostgresConfig] and
+/// [crate::config::RedisConfig].
 ///
-/// Also queries the max user id from both shards and stores it in internal state.
+/// Does not fail if dependencies are down. Shards are connected lazily and Redis is
+/// reconnected in the background.
 ///
 /// - Redis can be accessed via [crate::db::redis()]
 /// - Postgres can be accessed via [crate::db::postgres]
 pub async fn init() {
   // init connections
-  let redis_url = var("REDIS_URL").expect("REDIS_URL env var");
-  let pg_shard1 = var("PG_SHARD1").expect("PG_SHARD1 env var");
-  let pg_shard2 = var("PG_SHARD2").expect("PG_SHARD2 env var");
-
-  let mut state = DBState {
-    redis: Mutex::new(Redis::open(redis_url).expect("Failed to connect to Redis")),
-    shard1: PgPool::connect(&pg_shard1)
-      .await
-      .expect("Failed to connect to Postgres shard 1"),
-    shard2: PgPool::connect(&pg_shard2)
-      .await
-      .expect("Failed to connect to Postgres shard 2"),
-    max_uid: AtomicI32::new(0),
-  };
+  let config = config();
 
-  // get max uid among all shards
-  let shard_1_max_uid: i32 = query("SELECT COALESCE(MAX(\"id\"), 0) FROM \"user\"")
-    .fetch_one(&state.shard1)
-    .await
-    .map(|row| row.get(0))
-    .expect("Failed to get max uid from shard 1");
+  let redis_client = Redis::open(config.redis.url.as_str()).expect("Invalid Redis url");
 
-  let shard_2_max_uid: i32 = query("SELECT COALESCE(MAX(\"id\"), 0) FROM \"user\"")
-    .fetch_one(&state.shard2)
-    .await
-    .map(|row| row.get(0))
-    .expect("Failed to get max uid from shard 2");
+  let shards: Vec<_> = config
+    .postgres
+    .shards
+    .iter()
+    .map(|shard| connect_lazy(&shard.url))
+    .collect();
 
-  state.max_uid = AtomicI32::new(shard_1_max_uid.max(shard_2_max_uid));
+  let replicas: Vec<Vec<_>> = config
+    .postgres
+    .shards
+    .iter()
+    .map(|shard| shard.replicas.iter().map(|url| connect_lazy(url)).collect())
+    .collect();
+
+  let state = DBState {
+    redis_client,
+    redis: OnceLock::new(),
+    health: Health::new(&replicas),
+    shards,
+    replicas,
+    next_replica: AtomicUsize::new(0),
+    overrides: RwLock::default(),
+  };
 
   if DB_STATE.set(state).is_err() {
     panic!("Failed to initialize the app state!");
   }
+
+  health::check().await;
+  health::spawn_monitor();
+
+  info!(
+    "Initialized {} Postgres shards with {} read replicas",
+    self::shards().len(),
+    get().replicas.iter().map(Vec::len).sum::<usize>()
+  );
+}
+
+/// Sample connection usage of every shard and replica pool. See [crate::metrics]
+pub fn record_pool_metrics() {
+  let state = get();
+
+  for (i, primary) in state.shards.iter().enumerate() {
+    let replicas = state.replicas[i]
+      .iter()
+      .enumerate()
+      .map(|(r, pool)| (format!("replica{r}"), pool));
+
+    for (name, pool) in [("primary".to_string(), primary)]
+      .into_iter()
+      .chain(replicas)
+    {
+      let labels = [("shard", i.to_string()), ("pool", name)];
+
+      let size = pool.size();
+      let in_use = size.saturating_sub(pool.num_idle() as u32);
+      let max = pool.options().get_max_connections();
+
+      gauge!("postgres_pool_connections", &labels).set(size);
+      gauge!("postgres_pool_in_use_connections", &labels).set(in_use);
+      gauge!("postgres_pool_max_connections", &labels).set(max);
+      gauge!("postgres_pool_saturation", &labels).set(in_use as f64 / max.max(1) as f64);
+    }
+  }
+}
+
+/// Apply deferred cache invalidations and close all Postgres pools. Called on shutdown,
+/// after in-flight requests are done
+#[instrument(name = "db::close")]
+pub async fn close() {
+  cache::flush_pending().await;
+
+  let pending = cache::pending();
+
+  if pending > 0 {
+    // stale entries expire with their TTL
+    warn!("Redis is down, {pending} cache invalidations are lost");
+  }
+
+  let state = get();
+  let pools = state.shards.iter().chain(state.replicas.iter().flatten());
+
+  if timeout(PG_CLOSE_TIMEOUT, join_all(pools.map(PgPool::close)))
+    .await
+    .is_err()
+  {
+    warn!("Postgres connections are still in use, dropping them");
+  }
+
+  info!("Closed Postgres pools");
+}
+
+fn redis_config() -> ConnectionManagerConfig {
+  ConnectionManagerConfig::new()
+    .set_response_timeout(REDIS_RESPONSE_TIMEOUT)
+    .set_connection_timeout(REDIS_CONNECTION_TIMEOUT)
+    .set_number_of_retries(REDIS_RECONNECT_RETRIES)
 }
 
 fn get() -> &'static DBState {
   DB_STATE.get().unwrap()
 }
 
-/// Get Redis connection
-pub fn redis() -> &'static Mutex<Redis> {
-  &get().redis
+/// Get Redis connection, unless Redis is down. Clones are cheap and share the same
+/// multiplexed connection.
+///
+/// Callers must keep working without Redis. Report failed commands with
+/// [health::redis_failed], so other requests don't wait for timeouts.
+pub fn redis() -> Option<ConnectionManager> {
+  if !health::redis_up() {
+    return None;
+  }
+
+  get().redis.get().cloned()
 }
 
-/// Increment max user id among all shards returning current value.
-///
-/// This functions is intentionally the only way to aquire max user id.
+/// Allocate a new user id. Id also decides which shard the user is stored on.
 ///
-/// We must increment it as soon as we aquire in order to avoid collisions.
-/// Those may happen if users aquired same value but increment logic executes later
-/// (e.g. after insert).
+/// Ids come from the `user_id_seq` sequence on the [metadata] shard, so they are unique
+/// across all app instances.
 ///
 /// In worst case scenario (if id is aquired but not used) we will have a gap in ids which
 /// is fine (better than having a collision).
-pub fn fetch_add_max_uid() -> i32 {
-  get().max_uid.fetch_add(1, Ordering::SeqCst) + 1
+pub async fn next_user_id() -> Result<i32> {
+  let user_id = query_scalar("SELECT nextval('user_id_seq')::integer")
+    .fetch_one(metadata())
+    .await?;
+
+  Ok(user_id)
+}
+
+/// Statements creating the user id sequence. Only applied to the metadata shard
+fn create_user_id_sequence() -> Vec<String> {
+  vec!["CREATE SEQUENCE IF NOT EXISTS \"user_id_seq\" AS integer".into()]
+}
+
+fn drop_user_id_sequence() -> Vec<String> {
+  vec!["DROP SEQUENCE IF EXISTS \"user_id_seq\"".into()]
+}
+
+/// Data migration moving the user id sequence past ids of the shard which were assigned
+/// before the sequence existed. Only ever moves the sequence forward, so it is safe while
+/// other instances allocate ids
+fn sync_user_id_sequence(conn: &mut PgConnection, index: usize) -> BoxFuture<'_, sqlx::Result<()>> {
+  Box::pin(async move {
+    let max_id: i32 = query_scalar("SELECT COALESCE(MAX(\"id\"), 0) FROM \"user\"")
+      .fetch_one(&mut *conn)
+      .await?;
+
+    if max_id == 0 {
+      return Ok(());
+    }
+
+    let sync_query = query(
+      "SELECT setval('user_id_seq', $1) FROM \"user_id_seq\" \
+       WHERE last_value < $1 OR (last_value = $1 AND NOT is_called)",
+    )
+    .bind(max_id);
+
+    // the sequence may have been created earlier in the same transaction
+    if index == METADATA_SHARD {
+      sync_query.fetch_optional(conn).await?;
+    } else {
+      sync_query.fetch_optional(metadata()).await?;
+    }
+
+    Ok(())
+  })
+}
+
+/// All Postgres shards
+pub fn shards() -> &'static [PgPool] {
+  &get().shards
+}
+
+/// Read replicas of the shard
+pub fn replicas(index: usize) -> &'static [PgPool] {
+  &get().replicas[index]
 }
 
-/// Get Postgres shard connection based on user id parity
-pub fn postgres(user_id: i32) -> &'static PgPool {
-  if user_id % 2 == 0 {
-    &get().shard2
-  } else {
-    &get().shard1
+/// Index of the [metadata] shard
+pub const METADATA_SHARD: usize = 0;
+
+/// Shard which stores cluster wide state, e.g. shard overrides and email directory
+pub fn metadata() -> &'static PgPool {
+  &shards()[METADATA_SHARD]
+}
+
+/// Index of the shard which stores the user.
+///
+/// Users moved with [reshard::move_user] are looked up in overrides. Other ids are
+/// distributed round-robin starting from the first shard. With two shards this keeps odd
+/// ids on the first shard and even ids on the second one.
+pub fn shard_index(user_id: i32) -> usize {
+  if let Some(shard) = get().overrides.read().get(&user_id) {
+    return *shard;
+  }
+
+  (user_id - 1).rem_euclid(shards().len() as i32) as usize
+}
+
+/// Get Postgres shard by index. Fails with [Error::ShardUnavailable] if the shard is down
+pub fn shard(index: usize) -> Result<&'static PgPool> {
+  if !shard_up(index) {
+    return Err(Error::ShardUnavailable);
+  }
+
+  Ok(&shards()[index])
+}
+
+/// Get Postgres shard connection based on user id
+pub fn postgres(user_id: i32) -> Result<&'static PgPool> {
+  shard(shard_index(user_id))
+}
+
+fn primary_pin_key(user_id: i32) -> String {
+  format!("{user_id}:pin_primary")
+}
+
+/// Route reads of the user to the primary for [PRIMARY_PIN_WINDOW]. Must be called after
+/// writes which the user expects to read back
+pub async fn pin_primary(user_id: i32) {
+  cache::set(
+    &primary_pin_key(user_id),
+    true,
+    PRIMARY_PIN_WINDOW.as_secs(),
+  )
+  .await;
+}
+
+/// Get Postgres connection for read-only queries of the user.
+///
+/// Picks a healthy read replica of the user's shard round-robin. Falls back to the
+/// primary if the shard has no healthy replicas, the user has written recently (see
+/// [pin_primary]) or Redis is down, so the pin can't be checked.
+///
+/// Results may only be cached if the pool is picked after the bump of a pinned write,
+/// see [cache].
+pub async fn postgres_read(user_id: i32) -> Result<&'static PgPool> {
+  let index = shard_index(user_id);
+  let replicas = &get().replicas[index];
+
+  if replicas.is_empty() || redis().is_none() {
+    return shard(index);
+  }
+
+  if cache::get::<bool>(&primary_pin_key(user_id))
+    .await
+    .unwrap_or_default()
+  {
+    return shard(index);
+  }
+
+  let start = get().next_replica.fetch_add(1, Ordering::Relaxed);
+
+  let replica = (0..replicas.len())
+    .map(|i| (start + i) % replicas.len())
+    .find(|&i| replica_up(index, i));
+
+  match replica {
+    Some(i) => Ok(&replicas[i]),
+    None => shard(index),
   }
 }
 
+/// Run a query on every shard concurrently. Results are returned in shard order.
+///
+/// Fails if at least one shard fails, since the result would be incomplete.
+pub async fn for_each_shard<T, E, F, Fut>(f: F) -> Result<Vec<T>>
+where
+  F: Fn(&'static PgPool) -> Fut,
+  Fut: Future<Output = core::result::Result<T, E>>,
+  Error: From<E>,
+{
+  if !(0..shards().len()).all(shard_up) {
+    return Err(Error::ShardUnavailable);
+  }
+
+  let results = try_join_all(shards().iter().map(f)).await;
+
+  Ok(results?)
+}
+
+/// Bring shards to the latest schema version, see [migrations].
+///
+/// The [metadata] shard is a hard dependency, since it stores the email directory and
+/// allocates user ids. Its migration is retried with backoff while it is unreachable and
+/// startup fails afterwards. Other shards which can't be migrated stay unavailable, see
+/// [shard_up], and are migrated in the background once they are reachable.
 #[instrument]
 pub async fn run_migrations() -> Result {
-  info!("Running migrations for shard 1");
-  let db = &get().shard1;
+  if !config().postgres.migrate_on_startup {
+    info!("Skipping migrations on startup");
+  }
+
+  let mut delay = STARTUP_RETRY_DELAY;
+  let mut attempt = 1;
+
+  loop {
+    match migrations::migrate_shard_on_startup(METADATA_SHARD).await {
+      Err(e @ (Error::Db(_) | Error::ShardUnavailable)) if attempt < STARTUP_RETRIES => {
+        warn!("Migrations failed (attempt {attempt}), retrying in {delay:?}: {e}");
+      }
+      res => break res?,
+    }
+
+    tokio::time::sleep(delay).await;
+    delay = (delay * 2).min(STARTUP_MAX_RETRY_DELAY);
+    attempt += 1;
+  }
+
+  health::set_migrated(METADATA_SHARD);
+
+  let mut pending = vec![];
 
-  create_user_tables(db).await?;
-  create_chat_tables(db).await?;
+  for index in (0..shards().len()).filter(|&i| i != METADATA_SHARD) {
+    if !try_migrate_shard(index).await {
+      pending.push(index);
+    }
+  }
 
-  info!("Running migrations for shard 2");
-  let db = &get().shard2;
+  if !pending.is_empty() {
+    spawn_pending_migrations(pending);
+  }
 
-  create_user_tables(db).await?;
-  create_chat_tables(db).await?;
+  Ok(())
+}
+
+/// Mark shards available without migrating them, for CLI commands. Fails unless all
+/// shards are at the latest version
+pub async fn check_migrations() -> Result {
+  for index in 0..shards().len() {
+    migrations::check_version(index).await?;
+    health::set_migrated(index);
+  }
 
   Ok(())
 }
+
+/// Migrate the shard and mark it available. Returns whether it succeeded
+async fn try_migrate_shard(index: usize) -> bool {
+  match migrations::migrate_shard_on_startup(index).await {
+    Ok(()) => {
+      health::set_migrated(index);
+      true
+    }
+    Err(e) => {
+      warn!(
+        "Shard {} is unavailable until it is migrated: {e}",
+        index + 1
+      );
+      false
+    }
+  }
+}
+
+/// Retry migrations of shards which were unavailable on startup
+fn spawn_pending_migrations(mut pending: Vec<usize>) {
+  tokio::spawn(async move {
+    while !pending.is_empty() {
+      tokio::time::sleep(MIGRATION_RETRY_INTERVAL).await;
+
+      let mut failed = vec![];
+
+      for index in pending {
+        if !health::shard_reachable(index) || !try_migrate_shard(index).await {
+          failed.push(index);
+        }
+      }
+
+      pending = failed;
+    }
+  });
+}
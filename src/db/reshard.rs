@@ -0,0 +1,591 @@
+//! Online resharding
+//!
+//! Users may be moved from the shard picked by [super::shard_index] to any other shard.
+//! Such users are recorded in the `shard_override` table on the [metadata] shard.
+//! Every instance keeps a copy of overrides in memory and refreshes it periodically.
+//!
+//! Ids of rows in auto increment tables must be unique across all shards, so moved rows
+//! keep their ids. To achieve that, sequences of every shard step by [ID_STRIDE] and
+//! start at a different offset, see [align_id_sequences]. Rows created before sequences
+//! were aligned may still collide, in that case the move is rejected, since chat and
+//! message ids must survive the move.
+
+use super::{cache, get, metadata, shard_index, shards};
+use crate::{
+  chat::{chats_cache_key, messages_cache_key},
+  result::{Error, Result},
+  user::{auth::migrating_cache_key, directory},
+};
+use futures::future::BoxFuture;
+use sea_query::{ColumnDef, Iden, OnConflict, PostgresQueryBuilder, Query, Table};
+use serde::Serialize;
+use serde_json::{self as json, Value};
+use sqlx::{query, query_scalar, PgConnection};
+use std::{collections::HashMap, time::Duration};
+use tracing::{error, info, instrument, warn};
+use ts_rs::TS;
+
+/// Max number of shards. Sequences of all shards step by this value
+pub const ID_STRIDE: i64 = 64;
+
+/// How often instances reload shard overrides
+const OVERRIDES_REFRESH: Duration = Duration::from_secs(5);
+
+/// Requests of a user being moved are rejected for at most this long
+const MOVE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Tables with auto increment ids which are stored on user shard
+const SEQUENCE_TABLES: [&str; 5] = [
+  "chat",
+  "message",
+  "recovery_code",
+  "audit_log",
+  "token_usage",
+];
+
+#[derive(Iden)]
+enum ShardOverrideIden {
+  #[iden = "shard_override"]
+  Table,
+  UserId,
+  Shard,
+}
+
+/// Statements creating `shard_override` table. Only applied to the metadata shard
+pub fn create_tables() -> Vec<String> {
+  let shard_override_table = Table::create()
+    .table(ShardOverrideIden::Table)
+    .if_not_exists()
+    .col(
+      ColumnDef::new(ShardOverrideIden::UserId)
+        .integer()
+        .not_null()
+        .primary_key(),
+    )
+    .col(
+      ColumnDef::new(ShardOverrideIden::Shard)
+        .small_integer()
+        .not_null(),
+    )
+    .to_string(PostgresQueryBuilder);
+
+  vec![shard_override_table]
+}
+
+pub fn drop_tables() -> Vec<String> {
+  vec![Table::drop()
+    .table(ShardOverrideIden::Table)
+    .if_exists()
+    .to_string(PostgresQueryBuilder)]
+}
+
+/// Data migration making sequences of the shard produce ids unique across all shards.
+///
+/// Shard `i` produces ids `≡ i + 1 (mod ID_STRIDE)` starting above its own max id, so
+/// new rows never collide, see the [module docs](self) for older rows. Already aligned
+/// sequences are skipped, so a migration adding a table to [SEQUENCE_TABLES] can run it
+/// again.
+pub fn align_id_sequences(
+  conn: &mut PgConnection,
+  index: usize,
+) -> BoxFuture<'_, sqlx::Result<()>> {
+  Box::pin(async move {
+    for table in SEQUENCE_TABLES {
+      let max_id: i64 = query_scalar(&format!(
+        "SELECT COALESCE(MAX(\"id\"), 0)::bigint FROM \"{table}\""
+      ))
+      .fetch_one(&mut *conn)
+      .await?;
+
+      let sequence: String = query_scalar("SELECT pg_get_serial_sequence($1, 'id')")
+        .bind(format!("\"{table}\""))
+        .fetch_one(&mut *conn)
+        .await?;
+
+      let increment: i64 = query_scalar(
+        "SELECT increment_by FROM pg_sequences WHERE format('%I.%I', schemaname, sequencename) = $1",
+      )
+      .bind(&sequence)
+      .fetch_one(&mut *conn)
+      .await?;
+
+      if increment == ID_STRIDE {
+        continue;
+      }
+
+      let offset = index as i64 + 1;
+      let mut next_id = max_id - max_id % ID_STRIDE + offset;
+
+      if next_id <= max_id {
+        next_id += ID_STRIDE;
+      }
+
+      info!("Aligning {sequence} of shard {offset} to start at {next_id}");
+
+      query(&format!(
+        "ALTER SEQUENCE {sequence} INCREMENT BY {ID_STRIDE} RESTART WITH {next_id}"
+      ))
+      .execute(&mut *conn)
+      .await?;
+    }
+
+    Ok(())
+  })
+}
+
+/// Reload shard overrides from the metadata shard
+pub async fn load_overrides() -> Result {
+  let overrides_query = Query::select()
+    .from(ShardOverrideIden::Table)
+    .columns([ShardOverrideIden::UserId, ShardOverrideIden::Shard])
+    .to_string(PostgresQueryBuilder);
+
+  let overrides: HashMap<i32, usize> = sqlx::query_as::<_, (i32, i16)>(&overrides_query)
+    .fetch_all(metadata())
+    .await?
+    .into_iter()
+    .map(|(user_id, shard)| (user_id, shard as usize))
+    .collect();
+
+  *get().overrides.write() = overrides;
+
+  Ok(())
+}
+
+/// Periodically reload shard overrides, so users moved by other instances are routed right
+pub fn spawn_overrides_refresh() {
+  tokio::spawn(async {
+    loop {
+      tokio::time::sleep(OVERRIDES_REFRESH).await;
+
+      if let Err(e) = load_overrides().await {
+        error!("Failed to refresh shard overrides: {e}");
+      }
+    }
+  });
+}
+
+#[derive(TS, Debug, Serialize)]
+#[ts(export, export_to = "./index.ts")]
+pub struct MoveUserReport {
+  user_id: i32,
+  /// Zero based shard indexes
+  from: usize,
+  to: usize,
+  #[ts(type = "number")]
+  chats: u64,
+  #[ts(type = "number")]
+  messages: u64,
+}
+
+/// Rows copied by [copy_user_rows]
+#[derive(Debug, Default)]
+struct CopiedRows {
+  chats: u64,
+  messages: u64,
+  /// Audit entries written after the copy stay on the source shard, so only these are
+  /// removed from it
+  audit_log_ids: Vec<i64>,
+}
+
+/// Copy rows selected by `condition` from `table` on source to target as JSON,
+/// so all columns are copied without listing them.
+///
+/// Fails if ids of an auto increment table are taken on the target, see the
+/// [module docs](self). Returns ids of the copied rows
+async fn copy_rows(
+  table: &str,
+  condition: &str,
+  user_id: i32,
+  source: &mut PgConnection,
+  target: &mut PgConnection,
+) -> Result<Vec<i64>> {
+  let rows: String = query_scalar(&format!(
+    "SELECT COALESCE(jsonb_agg(t), '[]')::text FROM \"{table}\" t WHERE {condition}"
+  ))
+  .bind(user_id)
+  .fetch_one(&mut *source)
+  .await?;
+
+  let ids: Vec<i64> = json::from_str::<Vec<json::Map<String, Value>>>(&rows)?
+    .iter()
+    .filter_map(|row| row.get("id").and_then(Value::as_i64))
+    .collect();
+
+  if SEQUENCE_TABLES.contains(&table) {
+    let taken: Vec<i64> = query_scalar(&format!(
+      "SELECT \"id\"::bigint FROM \"{table}\" WHERE \"id\" = ANY($1) ORDER BY \"id\" LIMIT 10"
+    ))
+    .bind(&ids)
+    .fetch_all(&mut *target)
+    .await?;
+
+    if !taken.is_empty() {
+      return Err(Error::Reshard(format!(
+        "{table} ids of user {user_id} are already taken on the target shard: {taken:?}"
+      )));
+    }
+  }
+
+  let res = query(&format!(
+    "INSERT INTO \"{table}\" SELECT * FROM jsonb_populate_recordset(NULL::\"{table}\", $1::jsonb)"
+  ))
+  .bind(rows)
+  .execute(&mut *target)
+  .await;
+
+  match res {
+    Ok(_) => Ok(ids),
+    Err(sqlx::Error::Database(e)) if e.is_unique_violation() => Err(Error::Reshard(format!(
+      "{table} ids of user {user_id} are already taken on the target shard"
+    ))),
+    Err(e) => Err(e.into()),
+  }
+}
+
+/// Copy the user and all rows of the user. Order matters because of foreign keys
+async fn copy_user_rows(
+  user_id: i32,
+  source: &mut PgConnection,
+  target: &mut PgConnection,
+) -> Result<CopiedRows> {
+  let by_user = "\"user_id\" = $1";
+  let by_chat = "\"chat_id\" IN (SELECT \"id\" FROM \"chat\" WHERE \"user_id\" = $1)";
+
+  copy_rows("user", "\"id\" = $1", user_id, source, target).await?;
+  copy_rows("recovery_code", by_user, user_id, source, target).await?;
+
+  let chats = copy_rows("chat", by_user, user_id, source, target).await?;
+  let messages = copy_rows("message", by_chat, user_id, source, target).await?;
+
+  copy_rows("token_usage", by_user, user_id, source, target).await?;
+
+  let audit_log_ids = copy_rows("audit_log", by_user, user_id, source, target).await?;
+
+  Ok(CopiedRows {
+    chats: chats.len() as u64,
+    messages: messages.len() as u64,
+    audit_log_ids,
+  })
+}
+
+/// Move user with all their data to another shard.
+///
+/// Requests of the user are rejected with 503 while the move is in progress. Source rows
+/// are locked until the move is finished, so concurrent writes either make it to the
+/// target shard or fail, but are never lost silently.
+#[instrument]
+pub async fn move_user(user_id: i32, target_index: usize) -> Result<MoveUserReport> {
+  let source_index = shard_index(user_id);
+
+  if target_index >= shards().len() || target_index == source_index {
+    return Err(Error::Reshard(format!(
+      "invalid target shard {target_index}"
+    )));
+  }
+
+  cache::set(&migrating_cache_key(user_id), true, MOVE_TIMEOUT.as_secs()).await;
+
+  let report = match copy_user(user_id, source_index, target_index).await {
+    Ok(report) => report,
+    Err(e) => {
+      // crate error is not Send, so it can't be held across the await
+      tokio::spawn(async move { cache::invalidate(&migrating_cache_key(user_id)).await });
+
+      return Err(e);
+    }
+  };
+
+  // instances keep routing to the source shard until they refresh overrides
+  cache::set(
+    &migrating_cache_key(user_id),
+    true,
+    OVERRIDES_REFRESH.as_secs() * 2,
+  )
+  .await;
+
+  Ok(report)
+}
+
+async fn copy_user(
+  user_id: i32,
+  source_index: usize,
+  target_index: usize,
+) -> Result<MoveUserReport> {
+  let shards = shards();
+
+  let mut source = shards[source_index].begin().await?;
+  let mut target = shards[target_index].begin().await?;
+
+  // locks block inserts referencing the user or chats until source tx ends
+  let locked_users = query("SELECT 1 FROM \"user\" WHERE \"id\" = $1 FOR UPDATE")
+    .bind(user_id)
+    .fetch_optional(&mut *source)
+    .await?;
+
+  if locked_users.is_none() {
+    return Err(Error::NotFound);
+  }
+
+  let chat_ids: Vec<i32> =
+    query_scalar("SELECT \"id\" FROM \"chat\" WHERE \"user_id\" = $1 FOR UPDATE")
+      .bind(user_id)
+      .fetch_all(&mut *source)
+      .await?;
+
+  let CopiedRows {
+    chats,
+    messages,
+    audit_log_ids,
+  } = copy_user_rows(user_id, &mut source, &mut target).await?;
+
+  target.commit().await?;
+
+  // flip. from now on the target shard is the source of truth
+  let upsert_override_query = Query::insert()
+    .into_table(ShardOverrideIden::Table)
+    .columns([ShardOverrideIden::UserId, ShardOverrideIden::Shard])
+    .values_panic([user_id.into(), (target_index as i16).into()])
+    .on_conflict(
+      OnConflict::column(ShardOverrideIden::UserId)
+        .update_column(ShardOverrideIden::Shard)
+        .to_owned(),
+    )
+    .to_string(PostgresQueryBuilder);
+
+  let flip = async {
+    let mut tx = metadata().begin().await?;
+
+    query(&upsert_override_query).execute(&mut *tx).await?;
+    directory::set_shard(user_id, target_index, &mut tx).await?;
+
+    tx.commit().await?;
+
+    Ok::<_, Error>(())
+  };
+
+  // crate error is not Send, so it can't be held across awaits below
+  let flip_error = flip.await.err().map(|e| e.to_string());
+
+  if let Some(e) = flip_error {
+    error!("Failed to flip shard override, removing copied rows: {e}");
+
+    // user rows cascade to chats, messages, recovery codes and token usage
+    let mut cleanup = shards[target_index].begin().await?;
+
+    query("DELETE FROM \"audit_log\" WHERE \"user_id\" = $1")
+      .bind(user_id)
+      .execute(&mut *cleanup)
+      .await?;
+
+    query("DELETE FROM \"user\" WHERE \"id\" = $1")
+      .bind(user_id)
+      .execute(&mut *cleanup)
+      .await?;
+
+    cleanup.commit().await?;
+
+    return Err(Error::Reshard(format!(
+      "failed to flip shard override: {e}"
+    )));
+  }
+
+  get().overrides.write().insert(user_id, target_index);
+
+  // cleanup source. failure here leaves stale rows which are never routed to
+  let cleanup_source = async {
+    // audit log has no foreign key to the locked user row, so entries may be written
+    // during the copy. those are kept
+    query("DELETE FROM \"audit_log\" WHERE \"id\" = ANY($1)")
+      .bind(&audit_log_ids)
+      .execute(&mut *source)
+      .await?;
+
+    let remaining: i64 = query_scalar("SELECT COUNT(*) FROM \"audit_log\" WHERE \"user_id\" = $1")
+      .bind(user_id)
+      .fetch_one(&mut *source)
+      .await?;
+
+    if remaining > 0 {
+      warn!(
+        "{remaining} audit entries of user {user_id} were written during the move, \
+         they are kept on the source shard"
+      );
+    }
+
+    query("DELETE FROM \"user\" WHERE \"id\" = $1")
+      .bind(user_id)
+      .execute(&mut *source)
+      .await?;
+
+    source.commit().await
+  };
+
+  if let Err(e) = cleanup_source.await {
+    warn!("User {user_id} moved, but source shard cleanup failed: {e}");
+  }
+
+  // cached data may be repopulated from the source shard by lagging instances
+  cache::bump(&chats_cache_key(user_id)).await;
+
+  for chat_id in chat_ids {
+    cache::bump(&messages_cache_key(format!("{chat_id}-{user_id}"))).await;
+  }
+
+  info!(
+    user_id,
+    source_index, target_index, chats, messages, "user moved"
+  );
+
+  Ok(MoveUserReport {
+    user_id,
+    from: source_index,
+    to: target_index,
+    chats,
+    messages,
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::db::migrations;
+  use sqlx::{postgres::PgConnectOptions, query_as, Connection, Executor};
+  use std::{env::var, str::FromStr};
+
+  /// Connection to an empty schema with all tables of a user shard
+  async fn test_shard(url: &str, schema: &str) -> PgConnection {
+    let mut conn = PgConnection::connect(url).await.unwrap();
+
+    conn
+      .execute(format!("DROP SCHEMA IF EXISTS {schema} CASCADE; CREATE SCHEMA {schema}").as_str())
+      .await
+      .unwrap();
+
+    let options = PgConnectOptions::from_str(url)
+      .unwrap()
+      .options([("search_path", schema)]);
+
+    let mut conn = PgConnection::connect_with(&options).await.unwrap();
+
+    for statement in migrations::shard_schema() {
+      conn.execute(statement.as_str()).await.unwrap();
+    }
+
+    conn
+  }
+
+  /// Chat titles and texts of messages of the user
+  async fn messages_of(conn: &mut PgConnection, user_id: i32) -> Vec<(String, String)> {
+    query_as(
+      "SELECT c.title, m.text FROM message m JOIN chat c ON c.id = m.chat_id \
+       WHERE c.user_id = $1 ORDER BY m.text",
+    )
+    .bind(user_id)
+    .fetch_all(conn)
+    .await
+    .unwrap()
+  }
+
+  #[tokio::test]
+  #[ignore = "needs Postgres, set TEST_DATABASE_URL"]
+  async fn move_user_with_colliding_ids() {
+    let url = var("TEST_DATABASE_URL").unwrap();
+
+    let mut source = test_shard(&url, "reshard_test_source").await;
+    let mut target = test_shard(&url, "reshard_test_target").await;
+
+    // rows created before sequences were aligned, ids start at 1 on both shards
+    source
+      .execute(
+        r#"
+        INSERT INTO "user" (id, name, email, password) VALUES (1, 'moved', 'moved@test', '');
+        INSERT INTO chat (id, title, model, user_id) VALUES (1, 'first', 'm', 1), (2, 'second', 'm', 1);
+        INSERT INTO message (id, role, text, chat_id) VALUES (1, 0, 'a', 1), (2, 1, 'b', 1), (3, 1, 'c', 2);
+        INSERT INTO token_usage (id, user_id, message_id, model, prompt_tokens, completion_tokens,
+          total_duration, prompt_eval_duration, eval_duration)
+          VALUES (1, 1, 2, 'm', 1, 1, 0, 0, 0), (2, 1, 3, 'm', 1, 1, 0, 0, 0);
+        INSERT INTO audit_log (id, user_id, action) VALUES (1, 1, 0);
+        "#,
+      )
+      .await
+      .unwrap();
+
+    target
+      .execute(
+        r#"
+        INSERT INTO "user" (id, name, email, password) VALUES (2, 'other', 'other@test', '');
+        INSERT INTO chat (id, title, model, user_id) VALUES (1, 'other', 'm', 2);
+        INSERT INTO message (id, role, text, chat_id) VALUES (1, 0, 'other', 1);
+        INSERT INTO token_usage (id, user_id, message_id, model, prompt_tokens, completion_tokens,
+          total_duration, prompt_eval_duration, eval_duration)
+          VALUES (1, 2, 1, 'm', 1, 1, 0, 0, 0);
+        INSERT INTO audit_log (id, user_id, action) VALUES (1, 2, 0);
+        "#,
+      )
+      .await
+      .unwrap();
+
+    let Err(Error::Reshard(e)) = copy_user_rows(1, &mut source, &mut target).await else {
+      panic!("colliding ids must be rejected");
+    };
+    assert!(e.starts_with("chat ids of user 1 are already taken"), "{e}");
+
+    // ids are never rewritten, so chats and messages of neither user changed
+    let other = messages_of(&mut target, 2).await;
+    assert_eq!(other, [("other".to_string(), "other".to_string())]);
+
+    let moved = messages_of(&mut source, 1).await;
+    assert_eq!(
+      moved,
+      [("first", "a"), ("first", "b"), ("second", "c")].map(|(c, m)| (c.into(), m.into()))
+    );
+  }
+
+  #[tokio::test]
+  #[ignore = "needs Postgres, set TEST_DATABASE_URL"]
+  async fn move_user_keeps_ids() {
+    let url = var("TEST_DATABASE_URL").unwrap();
+
+    let mut source = test_shard(&url, "reshard_test_keep_source").await;
+    let mut target = test_shard(&url, "reshard_test_keep_target").await;
+
+    source
+      .execute(
+        r#"
+        INSERT INTO "user" (id, name, email, password) VALUES (1, 'moved', 'moved@test', '');
+        INSERT INTO chat (id, title, model, user_id) VALUES (65, 'first', 'm', 1);
+        INSERT INTO message (id, role, text, chat_id) VALUES (65, 0, 'a', 65), (129, 1, 'b', 65);
+        INSERT INTO audit_log (id, user_id, action) VALUES (65, 1, 0);
+        "#,
+      )
+      .await
+      .unwrap();
+
+    target
+      .execute(
+        r#"
+        INSERT INTO "user" (id, name, email, password) VALUES (2, 'other', 'other@test', '');
+        INSERT INTO chat (id, title, model, user_id) VALUES (66, 'other', 'm', 2);
+        INSERT INTO message (id, role, text, chat_id) VALUES (66, 0, 'other', 66);
+        "#,
+      )
+      .await
+      .unwrap();
+
+    let copied = copy_user_rows(1, &mut source, &mut target).await.unwrap();
+
+    assert_eq!(copied.chats, 1);
+    assert_eq!(copied.messages, 2);
+    assert_eq!(copied.audit_log_ids, [65]);
+
+    let moved: Vec<(i32, i32, String)> = query_as(
+      "SELECT m.chat_id, m.id, m.text FROM message m JOIN chat c ON c.id = m.chat_id \
+       WHERE c.user_id = 1 ORDER BY m.id",
+    )
+    .fetch_all(&mut target)
+    .await
+    .unwrap();
+    assert_eq!(moved, [(65, 65, "a".into()), (65, 129, "b".into())]);
+  }
+}
@@ -0,0 +1,51 @@
+//! Request extractors rejecting with [ErrorResponse](crate::result::ErrorResponse) bodies
+//!
+//! Rejections of axum extractors have plain text bodies. These wrappers turn them into
+//! [Error::BadRequest], so clients can handle all errors the same way.
+
+use crate::result::Error;
+use axum::{
+  extract::{
+    rejection::{JsonRejection, PathRejection, QueryRejection},
+    FromRequest, FromRequestParts,
+  },
+  response::{IntoResponse, Response},
+};
+use serde::Serialize;
+
+/// [axum::Json], also used for responses
+#[derive(Debug, FromRequest)]
+#[from_request(via(axum::Json), rejection(Error))]
+pub struct Json<T>(pub T);
+
+impl<T: Serialize> IntoResponse for Json<T> {
+  fn into_response(self) -> Response {
+    axum::Json(self.0).into_response()
+  }
+}
+
+#[derive(Debug, FromRequestParts)]
+#[from_request(via(axum::extract::Path), rejection(Error))]
+pub struct Path<T>(pub T);
+
+#[derive(Debug, FromRequestParts)]
+#[from_request(via(axum::extract::Query), rejection(Error))]
+pub struct Query<T>(pub T);
+
+impl From<JsonRejection> for Error {
+  fn from(rejection: JsonRejection) -> Self {
+    Error::BadRequest(rejection.body_text())
+  }
+}
+
+impl From<PathRejection> for Error {
+  fn from(rejection: PathRejection) -> Self {
+    Error::BadRequest(rejection.body_text())
+  }
+}
+
+impl From<QueryRejection> for Error {
+  fn from(rejection: QueryRejection) -> Self {
+    Error::BadRequest(rejection.body_text())
+  }
+}
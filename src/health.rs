@@ -0,0 +1,247 @@
+//! Liveness and readiness probes for load balancers and orchestrators
+//!
+//! - `/healthz` responds while the server is running
+//! - `/readyz` probes all dependencies and responds 503 unless the metadata shard and
+//!   at least one Ollama backend are up. Other shards, Redis and read replicas are
+//!   reported but not required, since only users of a down shard are affected and
+//!   requests bypass the others while they are down. Shards count as down until they
+//!   are migrated. Once shutdown has started it responds 503 without probing
+//!
+//! `/readyz` is unauthenticated, so it only reports whether dependencies are up and
+//! reuses probe results for [READINESS_TTL]. Errors, versions and backend urls are
+//! served to admins at `/api/admin/readyz`
+
+use crate::{
+  db::{
+    health::{probe_postgres, probe_redis, shard_migrated},
+    replicas, shards, METADATA_SHARD,
+  },
+  ollama::backends,
+  shutdown,
+  user::auth::Admin,
+};
+use axum::{http::StatusCode, routing::get, Json, Router};
+use futures::future::join_all;
+use serde::Serialize;
+use std::{
+  future::Future,
+  iter::once,
+  time::{Duration, Instant},
+};
+use tokio::{sync::Mutex, time::timeout};
+use tracing::instrument;
+use ts_rs::TS;
+
+/// Ollama may be busy generating, so it gets more time than the background checks
+const OLLAMA_PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// `/readyz` is unauthenticated, so its probes are reused for this long. Otherwise every
+/// request would hit all dependencies
+const READINESS_TTL: Duration = Duration::from_secs(2);
+
+static LAST_READINESS: Mutex<Option<(Instant, Readiness)>> = Mutex::const_new(None);
+
+#[derive(TS, Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[ts(export, export_to = "./index.ts")]
+#[serde(rename_all = "snake_case")]
+pub enum DependencyKind {
+  Postgres,
+  PostgresReplica,
+  Redis,
+  Ollama,
+}
+
+#[derive(TS, Debug, Clone, Serialize)]
+#[ts(export, export_to = "./index.ts")]
+pub struct DependencyStatus {
+  name: String,
+  kind: DependencyKind,
+  up: bool,
+  /// Whether robo is ready only while this dependency is up
+  required: bool,
+  latency_ms: f64,
+  /// Server version, if known
+  version: Option<String>,
+  /// Why the probe failed
+  error: Option<String>,
+}
+
+#[derive(TS, Debug, Clone, Serialize)]
+#[ts(export, export_to = "./index.ts")]
+pub struct Readiness {
+  ready: bool,
+  dependencies: Vec<DependencyStatus>,
+}
+
+impl Readiness {
+  fn status(&self) -> StatusCode {
+    match self.ready {
+      true => StatusCode::OK,
+      false => StatusCode::SERVICE_UNAVAILABLE,
+    }
+  }
+
+  /// Hide details which could help attackers, e.g. internal hosts and server versions
+  fn redacted(mut self) -> Self {
+    let mut ollama = 0;
+
+    for dependency in &mut self.dependencies {
+      dependency.version = None;
+      dependency.error = None;
+
+      if dependency.kind == DependencyKind::Ollama {
+        ollama += 1;
+        dependency.name = format!("ollama{ollama}");
+      }
+    }
+
+    self
+  }
+}
+
+/// Run the probe, measuring its latency. Probe returns the version of the dependency
+async fn probe(
+  name: String,
+  kind: DependencyKind,
+  required: bool,
+  probe: impl Future<Output = Result<Option<String>, String>>,
+) -> DependencyStatus {
+  let start = Instant::now();
+  let res = probe.await;
+  let latency_ms = start.elapsed().as_secs_f64() * 1000.0;
+
+  let (version, error) = match res {
+    Ok(version) => (version, None),
+    Err(e) => (None, Some(e)),
+  };
+
+  DependencyStatus {
+    name,
+    kind,
+    up: error.is_none(),
+    required,
+    latency_ms,
+    version,
+    error,
+  }
+}
+
+async fn probe_ollama(ollama: &ollama::Ollama) -> Result<Option<String>, String> {
+  match timeout(OLLAMA_PROBE_TIMEOUT, ollama.version()).await {
+    Ok(Ok(version)) => Ok(Some(version)),
+    Ok(Err(e)) => Err(e.to_string()),
+    Err(_) => Err("timed out".to_string()),
+  }
+}
+
+/// liveness probe
+async fn healthz() -> &'static str {
+  "ok"
+}
+
+/// Probe all dependencies now
+async fn readiness() -> Readiness {
+  let redis = probe("redis".to_string(), DependencyKind::Redis, false, async {
+    probe_redis().await.map(|_| None)
+  });
+
+  let postgres = shards()
+    .iter()
+    .enumerate()
+    .flat_map(|(i, shard)| {
+      let replicas = replicas(i).iter().enumerate().map(move |(j, replica)| {
+        let name = format!("shard{}_replica{}", i + 1, j + 1);
+
+        (name, DependencyKind::PostgresReplica, i, replica)
+      });
+
+      once((
+        format!("shard{}", i + 1),
+        DependencyKind::Postgres,
+        i,
+        shard,
+      ))
+      .chain(replicas)
+    })
+    .map(|(name, kind, i, pool)| {
+      let is_shard = kind == DependencyKind::Postgres;
+
+      probe(name, kind, is_shard && i == METADATA_SHARD, async move {
+        probe_postgres(pool).await?;
+
+        match is_shard && !shard_migrated(i) {
+          true => Err("not migrated".to_string()),
+          false => Ok(None),
+        }
+      })
+    });
+
+  let ollama = backends::clients().map(|(name, ollama)| {
+    probe(
+      name.to_string(),
+      DependencyKind::Ollama,
+      false,
+      probe_ollama(ollama),
+    )
+  });
+
+  let (redis, postgres, ollama) = tokio::join!(redis, join_all(postgres), join_all(ollama));
+
+  let ready = postgres.iter().all(|status| status.up || !status.required)
+    && ollama.iter().any(|status| status.up);
+
+  let dependencies: Vec<_> = [redis].into_iter().chain(postgres).chain(ollama).collect();
+
+  Readiness {
+    ready,
+    dependencies,
+  }
+}
+
+/// Last readiness if fresh, otherwise probe again. Concurrent requests wait for a single
+/// probe
+async fn cached_readiness() -> Readiness {
+  let mut last = LAST_READINESS.lock().await;
+
+  if let Some((probed_at, readiness)) = &*last {
+    if probed_at.elapsed() < READINESS_TTL {
+      return readiness.clone();
+    }
+  }
+
+  let readiness = readiness().await;
+  *last = Some((Instant::now(), readiness.clone()));
+
+  readiness
+}
+
+/// readiness probe with status of every dependency
+async fn readyz() -> (StatusCode, Json<Readiness>) {
+  // load balancers stop routing here before in-flight requests are drained
+  if shutdown::started() {
+    let readiness = Readiness {
+      ready: false,
+      dependencies: vec![],
+    };
+
+    return (readiness.status(), Json(readiness));
+  }
+
+  let readiness = cached_readiness().await.redacted();
+
+  (readiness.status(), Json(readiness))
+}
+
+/// readiness probe with errors and versions of dependencies
+#[instrument(name = "health::admin_readyz")]
+pub async fn admin_readyz(Admin(_): Admin) -> (StatusCode, Json<Readiness>) {
+  let readiness = readiness().await;
+
+  (readiness.status(), Json(readiness))
+}
+
+pub fn health_router() -> Router {
+  Router::new()
+    .route("/healthz", get(healthz))
+    .route("/readyz", get(readyz))
+}
@@ -1,32 +1,73 @@
-use crate::state;
+use crate::{config::config, state, user::schemas::UserRole};
 use jsonwebtoken::{decode, encode, errors::Result as JwtResult, Algorithm, Header, Validation};
 use serde::{Deserialize, Serialize};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Claims {
+  pub id: i32,
+  /// Role when the token was issued. Only informs clients, access checks use the role
+  /// stored in the DB, see [crate::user::auth::Admin]
+  #[serde(default)]
+  pub role: UserRole,
+  /// Issue time. Tokens issued before their user's tokens were revoked are rejected
+  #[serde(default)]
+  pub iat: u64,
+  pub exp: u64,
+}
+
+/// Claims of a token issued after password check for accounts with 2FA enabled.
+/// Can only be exchanged for a real token, `aud` makes [validate_jwt] reject it
 #[derive(Debug, Serialize, Deserialize)]
-struct Claims {
+struct TwoFactorClaims {
   id: i32,
+  aud: String,
   exp: u64,
 }
 
 const JWT_ALGO: Algorithm = Algorithm::HS512;
-const JWT_EXP: Duration = Duration::from_secs((24 * 60 * 60) * 32); // 32 days
 
-pub fn create_jwt(id: i32) -> String {
+const TWO_FACTOR_AUD: &str = "2fa";
+const TWO_FACTOR_EXP: Duration = Duration::from_secs(5 * 60); // 5 minutes
+
+pub fn create_jwt(id: i32, role: UserRole) -> String {
   let time_now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
 
   encode(
     &Header::new(JWT_ALGO),
     &Claims {
       id,
-      exp: (time_now + JWT_EXP).as_secs(),
+      role,
+      iat: time_now.as_secs(),
+      exp: (time_now + config().auth.jwt_lifetime()).as_secs(),
     },
     state::jwt_encode(),
   )
   .unwrap()
 }
 
-pub fn validate_jwt(token: &str) -> JwtResult<i32> {
-  decode::<Claims>(token, state::jwt_decode(), &Validation::new(JWT_ALGO))
-    .map(|data| data.claims.id)
+pub fn validate_jwt(token: &str) -> JwtResult<Claims> {
+  decode::<Claims>(token, state::jwt_decode(), &Validation::new(JWT_ALGO)).map(|data| data.claims)
+}
+
+pub fn create_2fa_jwt(id: i32) -> String {
+  let time_now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+
+  encode(
+    &Header::new(JWT_ALGO),
+    &TwoFactorClaims {
+      id,
+      aud: TWO_FACTOR_AUD.to_string(),
+      exp: (time_now + TWO_FACTOR_EXP).as_secs(),
+    },
+    state::jwt_encode(),
+  )
+  .unwrap()
+}
+
+pub fn validate_2fa_jwt(token: &str) -> JwtResult<i32> {
+  let mut validation = Validation::new(JWT_ALGO);
+  validation.set_audience(&[TWO_FACTOR_AUD]);
+
+  decode::<TwoFactorClaims>(token, state::jwt_decode(), &validation).map(|data| data.claims.id)
 }
@@ -1,63 +1,118 @@
+mod admin;
+mod audit;
 mod chat;
+mod config;
 mod db;
+mod extract;
+mod health;
 mod jwt;
+mod metrics;
 mod ollama;
+mod otel;
+mod pagination;
+mod request_id;
 mod result;
+mod shutdown;
 mod state;
+mod usage;
 mod user;
 
-use axum::Router;
+use axum::{http::HeaderValue, middleware::from_fn, Router};
 use std::{
-  env::{current_exe, var},
+  env::{args, current_exe},
+  net::SocketAddr,
   path::Path,
+  process::exit,
 };
 use tower_http::{
-  cors::CorsLayer,
+  cors::{AllowOrigin, CorsLayer},
   services::{ServeDir, ServeFile},
 };
 use tracing::info;
 
-// runs inside musl container
-#[cfg(not(all(target_arch = "x86_64", target_os = "linux", target_env = "musl")))]
-const HOST: &str = "127.0.0.1";
+fn cors(origins: &[String]) -> CorsLayer {
+  if origins.iter().any(|origin| origin == "*") {
+    return CorsLayer::permissive();
+  }
+
+  // origins are validated by config::init
+  let origins = origins
+    .iter()
+    .filter_map(|origin| HeaderValue::from_str(origin.trim_end_matches('/')).ok());
 
-#[cfg(all(target_arch = "x86_64", target_os = "linux", target_env = "musl"))]
-const HOST: &str = "0.0.0.0";
+  CorsLayer::permissive().allow_origin(AllowOrigin::list(origins))
+}
 
 #[tokio::main]
 async fn main() -> result::Result {
   #[cfg(debug_assertions)]
-  {
-    tracing_subscriber::fmt()
-      .with_max_level(tracing::Level::DEBUG)
-      .init();
-
-    dotenv::from_filename(".env.dev").ok();
-  }
+  dotenv::from_filename(".env.dev").ok();
 
   #[cfg(not(debug_assertions))]
-  {
-    tracing_subscriber::fmt().init();
+  dotenv::dotenv().ok();
 
-    dotenv::dotenv().ok();
+  let config = match config::init() {
+    Ok(config) => config,
+    Err(e) => {
+      eprintln!("{e}");
+      exit(1);
+    }
+  };
+
+  otel::init(&config.tracing);
+
+  let args: Vec<String> = args().skip(1).collect();
+
+  if args.iter().any(|arg| arg == "--print-config") {
+    print!("{}", config::print());
+    return Ok(());
   }
 
   db::init().await;
-  state::init(
-    var("OLLAMA_URL").expect("OLLAMA_URL env var"),
-    var("JWT_SECRET").expect("JWT_SECRET env"),
-  );
+
+  // `robo migrate ...` only runs migrations, see db::migrations
+  if let Some(("migrate", migrate_args)) = args.split_first().map(|(c, a)| (c.as_str(), a)) {
+    let res = db::migrations::cli(migrate_args).await;
+    otel::shutdown();
+
+    return res;
+  }
+
+  // `robo admin ...` manages users, see admin::cli
+  if let Some(("admin", admin_args)) = args.split_first().map(|(c, a)| (c.as_str(), a)) {
+    db::check_migrations().await?;
+
+    let res = admin::cli(admin_args).await;
+    otel::shutdown();
+
+    return res;
+  }
+
+  metrics::init(&config.metrics);
+  state::init(config);
+  ollama::backends::init(&config.ollama).await;
+  ollama::backends::spawn_monitor();
+  user::oidc::init(&config.oidc);
 
   db::run_migrations().await?;
+  db::reshard::load_overrides().await?;
+  db::reshard::spawn_overrides_refresh();
 
   let app = Router::new()
+    .merge(health::health_router())
     .nest(
       "/api",
       Router::new()
         .merge(ollama::ollama_router())
         .merge(user::user_router())
         .merge(chat::chat_router())
-        .layer(CorsLayer::permissive()),
+        .merge(admin::admin_router())
+        .merge(audit::audit_router())
+        .merge(usage::usage_router())
+        .route_layer(from_fn(metrics::track_http))
+        .route_layer(from_fn(otel::trace_http))
+        .layer(from_fn(request_id::request_id))
+        .layer(cors(&config.server.cors_origins)),
     )
     .fallback_service({
       let static_dir = current_exe()?
@@ -68,10 +123,34 @@ async fn main() -> result::Result {
       ServeDir::new(&static_dir).fallback(ServeFile::new(static_dir.join("index.html")))
     });
 
-  let listener = tokio::net::TcpListener::bind((HOST, 3000)).await?;
+  let app = match (metrics::enabled(), config.metrics.port) {
+    (false, _) => app,
+    // served to admins by admin::admin_router
+    (true, 0) => app,
+    (true, port) => {
+      metrics::spawn_listener(config.server.host, port).await?;
+      app
+    }
+  };
+
+  let listener = tokio::net::TcpListener::bind((config.server.host, config.server.port)).await?;
   info!("listening on: {}", listener.local_addr()?);
 
-  axum::serve(listener, app).await?;
+  let shutdown = shutdown::Shutdown::new();
+
+  // client address is required for login brute-force protection
+  let server = axum::serve(
+    listener,
+    app.into_make_service_with_connect_info::<SocketAddr>(),
+  )
+  .with_graceful_shutdown(shutdown.signal());
+
+  let res = tokio::select! {
+    res = server => res,
+    _ = shutdown.deadline(config.server.shutdown_timeout()) => Ok(()),
+  };
+
+  shutdown::cleanup().await;
 
-  Ok(())
+  Ok(res?)
 }
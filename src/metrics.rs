@@ -0,0 +1,195 @@
+//! Prometheus metrics
+//!
+//! Metrics are recorded where they are measured with the [metrics] macros and exported at
+//! `/metrics` when enabled in [MetricsConfig]. Without the exporter recording is a no-op.
+
+use crate::{config::MetricsConfig, db, user::auth::Admin};
+use axum::{
+  extract::{MatchedPath, Request},
+  http::header::CONTENT_TYPE,
+  middleware::Next,
+  response::{IntoResponse, Response},
+  routing::get,
+  Router,
+};
+use metrics::{counter, describe_counter, describe_gauge, describe_histogram, gauge, histogram};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use std::{
+  net::IpAddr,
+  sync::OnceLock,
+  time::{Duration, Instant},
+};
+use tracing::{error, info, instrument};
+
+const UPKEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+const LATENCY_BUCKETS: &[f64] = &[
+  0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0,
+];
+const TOKENS_PER_SECOND_BUCKETS: &[f64] =
+  &[1.0, 2.0, 5.0, 10.0, 20.0, 35.0, 50.0, 75.0, 100.0, 200.0];
+
+static HANDLE: OnceLock<PrometheusHandle> = OnceLock::new();
+
+/// Install the Prometheus recorder if metrics are enabled
+#[instrument(name = "metrics::init", skip_all)]
+pub fn init(config: &MetricsConfig) {
+  if !config.enabled {
+    return;
+  }
+
+  let handle = PrometheusBuilder::new()
+    .set_buckets_for_metric(Matcher::Suffix("_seconds".to_string()), LATENCY_BUCKETS)
+    .and_then(|builder| {
+      builder.set_buckets_for_metric(
+        Matcher::Suffix("_tokens_per_second".to_string()),
+        TOKENS_PER_SECOND_BUCKETS,
+      )
+    })
+    .and_then(|builder| builder.install_recorder())
+    .expect("Failed to install metrics recorder");
+
+  describe();
+
+  if HANDLE.set(handle).is_err() {
+    panic!("Failed to initialize metrics!");
+  }
+
+  tokio::spawn(async {
+    loop {
+      tokio::time::sleep(UPKEEP_INTERVAL).await;
+
+      if let Some(handle) = HANDLE.get() {
+        handle.run_upkeep();
+      }
+    }
+  });
+}
+
+fn describe() {
+  describe_counter!("http_requests_total", "HTTP requests by route and status");
+  describe_histogram!(
+    "http_request_duration_seconds",
+    "HTTP request latency by route"
+  );
+  describe_histogram!(
+    "ollama_request_duration_seconds",
+    "Ollama request latency by model and backend"
+  );
+  describe_counter!(
+    "ollama_errors_total",
+    "Failed Ollama requests by model and backend"
+  );
+  describe_counter!(
+    "ollama_timeouts_total",
+    "Generations which exceeded the Ollama timeout by model"
+  );
+  describe_counter!(
+    "ollama_tokens_total",
+    "Prompt and completion tokens by model"
+  );
+  describe_histogram!(
+    "ollama_generation_tokens_per_second",
+    "Completion tokens generated per second by model"
+  );
+  describe_gauge!("ollama_active_generations", "Generations in progress");
+  describe_counter!("cache_lookups_total", "Redis cache lookups by result");
+  describe_gauge!(
+    "postgres_pool_connections",
+    "Open connections by shard and pool"
+  );
+  describe_gauge!(
+    "postgres_pool_in_use_connections",
+    "Connections in use by shard and pool"
+  );
+  describe_gauge!(
+    "postgres_pool_max_connections",
+    "Connection limit by shard and pool"
+  );
+  describe_gauge!(
+    "postgres_pool_saturation",
+    "Share of the connection limit in use by shard and pool"
+  );
+}
+
+/// Whether `/metrics` should be served
+pub fn enabled() -> bool {
+  HANDLE.get().is_some()
+}
+
+async fn render() -> Response {
+  let Some(handle) = HANDLE.get() else {
+    return ().into_response();
+  };
+
+  // gauges sampled on scrape
+  db::record_pool_metrics();
+
+  (
+    [(CONTENT_TYPE, "text/plain; version=0.0.4")],
+    handle.render(),
+  )
+    .into_response()
+}
+
+/// `/metrics` of the main listener, see [MetricsConfig::port]
+pub async fn admin_render(Admin(_): Admin) -> Response {
+  render().await
+}
+
+pub fn metrics_router() -> Router {
+  Router::new().route("/metrics", get(render))
+}
+
+/// Serve `/metrics` on a separate listener
+pub async fn spawn_listener(host: IpAddr, port: u16) -> std::io::Result<()> {
+  let listener = tokio::net::TcpListener::bind((host, port)).await?;
+  info!("serving metrics on: {}", listener.local_addr()?);
+
+  tokio::spawn(async move {
+    if let Err(e) = axum::serve(listener, metrics_router()).await {
+      error!("Metrics listener failed: {e}");
+    }
+  });
+
+  Ok(())
+}
+
+/// Count requests and measure latency per route. Must be a route layer, so the matched
+/// route is known
+pub async fn track_http(req: Request, next: Next) -> Response {
+  let route = req
+    .extensions()
+    .get::<MatchedPath>()
+    .map_or_else(String::new, |path| path.as_str().to_string());
+  let method = req.method().to_string();
+
+  let start = Instant::now();
+  let res = next.run(req).await;
+  let elapsed = start.elapsed().as_secs_f64();
+
+  let status = res.status().as_u16().to_string();
+
+  counter!("http_requests_total", "method" => method.clone(), "route" => route.clone(), "status" => status)
+    .increment(1);
+  histogram!("http_request_duration_seconds", "method" => method, "route" => route).record(elapsed);
+
+  res
+}
+
+/// Counts a generation as active until dropped
+pub struct ActiveGeneration(());
+
+impl ActiveGeneration {
+  pub fn start() -> Self {
+    gauge!("ollama_active_generations").increment(1);
+
+    Self(())
+  }
+}
+
+impl Drop for ActiveGeneration {
+  fn drop(&mut self) {
+    gauge!("ollama_active_generations").decrement(1);
+  }
+}
@@ -0,0 +1,258 @@
+//! Pool of Ollama backends
+//!
+//! Backends are probed in the background with `list_local_models`, which also tells which
+//! models each of them has. Requests go to the healthy backend with the model and the
+//! fewest requests in flight, and fail over to the next one on errors.
+
+use crate::{
+  config::OllamaConfig,
+  otel,
+  result::{Error, Result},
+};
+use futures::future::join_all;
+use metrics::{counter, histogram};
+use ollama::{error::OllamaError, models::LocalModel, Auth, Ollama};
+use parking_lot::RwLock;
+use std::{
+  future::Future,
+  sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    OnceLock,
+  },
+  time::{Duration, Instant},
+};
+use tokio::time::timeout;
+use tracing::{info, info_span, instrument, warn, Instrument};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+const CHECK_TIMEOUT: Duration = Duration::from_secs(3);
+
+struct Backend {
+  /// Url without credentials, for logs
+  name: String,
+  ollama: Ollama,
+  up: AtomicBool,
+  in_flight: AtomicUsize,
+  /// Models from the last successful probe
+  models: RwLock<Vec<LocalModel>>,
+}
+
+impl Backend {
+  fn has_model(&self, model: &str) -> bool {
+    self
+      .models
+      .read()
+      .iter()
+      .any(|local| same_model(&local.name, model))
+  }
+}
+
+/// Decrements in-flight counter of the backend when the request is done
+struct InFlight<'a>(&'a AtomicUsize);
+
+impl<'a> InFlight<'a> {
+  fn start(counter: &'a AtomicUsize) -> Self {
+    counter.fetch_add(1, Ordering::Relaxed);
+
+    Self(counter)
+  }
+}
+
+impl Drop for InFlight<'_> {
+  fn drop(&mut self) {
+    self.0.fetch_sub(1, Ordering::Relaxed);
+  }
+}
+
+static BACKENDS: OnceLock<Vec<Backend>> = OnceLock::new();
+
+fn backends() -> &'static [Backend] {
+  BACKENDS.get().expect("Ollama backends are not initialized")
+}
+
+/// Model names without a tag refer to the `latest` tag
+fn same_model(a: &str, b: &str) -> bool {
+  let tagged = |name: &str| match name.contains(':') {
+    true => name.to_string(),
+    false => format!("{name}:latest"),
+  };
+
+  tagged(a) == tagged(b)
+}
+
+/// Create clients of all backends and probe them once. Does not fail if backends are down
+#[instrument(name = "ollama::init", skip_all)]
+pub async fn init(config: &OllamaConfig) {
+  let pool = config
+    .urls
+    .iter()
+    .map(|url| {
+      // validated by config::init
+      let ollama = Ollama::try_new(url)
+        .expect("Invalid Ollama url")
+        .with_headers_hook(otel::inject_context);
+
+      let ollama = match config.bearer_token.as_str() {
+        "" => ollama,
+        token => ollama
+          .with_auth(Auth::Bearer(token.to_string()))
+          .expect("Invalid Ollama bearer token"),
+      };
+
+      Backend {
+        name: ollama.url_str().to_string(),
+        ollama,
+        up: AtomicBool::new(false),
+        in_flight: AtomicUsize::new(0),
+        models: RwLock::default(),
+      }
+    })
+    .collect();
+
+  if BACKENDS.set(pool).is_err() {
+    panic!("Failed to initialize Ollama backends!");
+  }
+
+  check().await;
+
+  info!("Initialized {} Ollama backends", backends().len());
+}
+
+async fn check_backend(backend: &Backend) {
+  let models = timeout(CHECK_TIMEOUT, backend.ollama.list_local_models()).await;
+
+  let up = match models {
+    Ok(Ok(models)) => {
+      *backend.models.write() = models;
+      true
+    }
+    _ => false,
+  };
+
+  match (backend.up.swap(up, Ordering::Relaxed), up) {
+    (false, true) => info!("Ollama {} is up", backend.name),
+    (true, false) => warn!("Ollama {} is down", backend.name),
+    _ => {}
+  }
+}
+
+/// Probe all backends once, concurrently so a hanging one doesn't delay the others
+async fn check() {
+  join_all(backends().iter().map(check_backend)).await;
+}
+
+/// Keep probing backends in the background
+pub fn spawn_monitor() {
+  tokio::spawn(async {
+    loop {
+      tokio::time::sleep(CHECK_INTERVAL).await;
+
+      check().await;
+    }
+  });
+}
+
+/// Clients of all backends with their names, for probing
+pub fn clients() -> impl Iterator<Item = (&'static str, &'static Ollama)> {
+  backends()
+    .iter()
+    .map(|backend| (backend.name.as_str(), &backend.ollama))
+}
+
+/// Models of all healthy backends, without duplicates
+pub fn models() -> Vec<LocalModel> {
+  let mut models: Vec<LocalModel> = vec![];
+
+  for backend in backends() {
+    if !backend.up.load(Ordering::Relaxed) {
+      continue;
+    }
+
+    for model in backend.models.read().iter() {
+      if !models
+        .iter()
+        .any(|known| same_model(&known.name, &model.name))
+      {
+        models.push(model.clone());
+      }
+    }
+  }
+
+  models.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+
+  models
+}
+
+/// Run the request on a healthy backend having the model, least busy first. Tries the
+/// next backend if the request fails, so it must be safe to repeat.
+///
+/// Fails with [Error::UnknownModel] if no backend has the model and with
+/// [Error::OllamaUnavailable] if all backends having it are down. Models of down backends
+/// are unknown, so a missing model is only reported if all backends are up.
+pub async fn with_backend<T, F, Fut>(model: &str, request: F) -> Result<T>
+where
+  F: Fn(&'static Ollama) -> Fut,
+  Fut: Future<Output = core::result::Result<T, OllamaError>>,
+{
+  let with_model: Vec<_> = backends()
+    .iter()
+    .filter(|backend| backend.has_model(model))
+    .collect();
+
+  if with_model.is_empty() {
+    return match backends().iter().all(|b| b.up.load(Ordering::Relaxed)) {
+      true => Err(Error::UnknownModel(model.to_string())),
+      false => Err(Error::OllamaUnavailable),
+    };
+  }
+
+  let mut candidates: Vec<_> = with_model
+    .into_iter()
+    .filter(|backend| backend.up.load(Ordering::Relaxed))
+    .collect();
+
+  candidates.sort_by_key(|backend| backend.in_flight.load(Ordering::Relaxed));
+
+  let attempts = candidates.len();
+
+  for (i, backend) in candidates.into_iter().enumerate() {
+    let _in_flight = InFlight::start(&backend.in_flight);
+
+    let start = Instant::now();
+    let span = info_span!(
+      "ollama::request",
+      otel.kind = "client",
+      model,
+      backend = backend.name,
+    );
+
+    let res = request(&backend.ollama).instrument(span).await;
+
+    let labels = [
+      ("model", model.to_string()),
+      ("backend", backend.name.clone()),
+    ];
+    histogram!("ollama_request_duration_seconds", &labels).record(start.elapsed().as_secs_f64());
+
+    let e = match res {
+      Ok(res) => return Ok(res),
+      Err(e) => e,
+    };
+
+    counter!("ollama_errors_total", &labels).increment(1);
+
+    // stop routing to the backend until the next successful probe
+    if matches!(&e, OllamaError::ReqwestError(e) if e.is_connect() || e.is_timeout()) {
+      warn!("Ollama {} is down", backend.name);
+      backend.up.store(false, Ordering::Relaxed);
+    }
+
+    if i + 1 == attempts {
+      return Err(e.into());
+    }
+
+    warn!("Ollama {} failed, trying next backend: {e}", backend.name);
+  }
+
+  Err(Error::OllamaUnavailable)
+}
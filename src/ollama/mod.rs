@@ -1,15 +1,14 @@
 //! Ollama API
 
-use crate::{result::Result, state::ollama};
+pub mod backends;
+
+use crate::result::Result;
 use axum::{routing::get, Json, Router};
 use ollama::models::LocalModel;
 
+/// models of all healthy backends
 async fn get_models() -> Result<Json<Vec<LocalModel>>> {
-  let ollama = ollama();
-
-  let models = ollama.list_local_models().await?;
-
-  Ok(Json(models))
+  Ok(Json(backends::models()))
 }
 
 pub fn ollama_router() -> Router {
@@ -0,0 +1,118 @@
+//! Logging and OpenTelemetry trace export
+//!
+//! Spans are exported over OTLP/HTTP when [TracingConfig::otlp_endpoint] is set. W3C trace
+//! context of incoming requests is continued by [trace_http] and passed on to Ollama by
+//! [inject_context], so a request can be followed through auth, cache, shard queries and
+//! the Ollama call.
+
+use crate::{config::TracingConfig, request_id};
+use axum::{
+  extract::{MatchedPath, Request},
+  http::HeaderMap,
+  middleware::Next,
+  response::Response,
+};
+use opentelemetry::{global, trace::TracerProvider};
+use opentelemetry_http::{HeaderExtractor, HeaderInjector};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{propagation::TraceContextPropagator, trace::SdkTracerProvider, Resource};
+use std::sync::OnceLock;
+use tracing::{field::Empty, info_span, level_filters::LevelFilter, Instrument, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt};
+
+static PROVIDER: OnceLock<SdkTracerProvider> = OnceLock::new();
+
+/// Install the log subscriber, exporting spans if configured
+pub fn init(config: &TracingConfig) {
+  let level = match cfg!(debug_assertions) {
+    true => LevelFilter::DEBUG,
+    false => LevelFilter::INFO,
+  };
+
+  let otel_layer = (!config.otlp_endpoint.is_empty()).then(|| {
+    let endpoint = format!("{}/v1/traces", config.otlp_endpoint.trim_end_matches('/'));
+
+    let exporter = SpanExporter::builder()
+      .with_http()
+      .with_endpoint(endpoint)
+      .build()
+      .expect("Failed to create OTLP exporter");
+
+    let provider = SdkTracerProvider::builder()
+      .with_resource(
+        Resource::builder()
+          .with_service_name(config.service_name.clone())
+          .build(),
+      )
+      .with_batch_exporter(exporter)
+      .build();
+
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let tracer = provider.tracer("robo");
+
+    if PROVIDER.set(provider).is_err() {
+      panic!("Failed to initialize trace export!");
+    }
+
+    tracing_opentelemetry::layer().with_tracer(tracer)
+  });
+
+  tracing_subscriber::registry()
+    .with(level)
+    .with(fmt::layer())
+    .with(otel_layer)
+    .init();
+}
+
+/// Export remaining spans. Must be called before exit
+pub fn shutdown() {
+  let Some(provider) = PROVIDER.get() else {
+    return;
+  };
+
+  if let Err(e) = provider.shutdown() {
+    eprintln!("Failed to export remaining spans: {e}");
+  }
+}
+
+/// Wrap the request in a span continuing the trace of the caller, if any. Must be a
+/// route layer, so the matched route is known
+pub async fn trace_http(req: Request, next: Next) -> Response {
+  let route = req
+    .extensions()
+    .get::<MatchedPath>()
+    .map_or_else(String::new, |path| path.as_str().to_string());
+  let method = req.method().to_string();
+
+  let span = info_span!(
+    "request",
+    otel.name = format!("{method} {route}"),
+    otel.kind = "server",
+    http.request.method = method,
+    http.route = route,
+    http.response.status_code = Empty,
+    request_id = request_id::current(),
+  );
+
+  let parent = global::get_text_map_propagator(|propagator| {
+    propagator.extract(&HeaderExtractor(req.headers()))
+  });
+  span.set_parent(parent);
+
+  let res = next.run(req).instrument(span.clone()).await;
+
+  span.record("http.response.status_code", res.status().as_u16());
+
+  res
+}
+
+/// Add trace context of the current span to request headers. See [ollama::HeadersHook]
+pub fn inject_context(headers: &mut HeaderMap) {
+  let context = Span::current().context();
+
+  global::get_text_map_propagator(|propagator| {
+    propagator.inject_context(&context, &mut HeaderInjector(headers))
+  });
+}
@@ -0,0 +1,31 @@
+//! Pagination query params
+
+use serde::Deserialize;
+use ts_rs::TS;
+use validator::Validate;
+
+#[derive(TS, Debug, Deserialize, Validate)]
+#[ts(export, export_to = "./index.ts")]
+pub struct Pagination {
+  /// Zero based page index
+  #[serde(default)]
+  pub page: u32,
+
+  #[serde(default = "default_per_page")]
+  #[validate(range(min = 1, max = 100))]
+  pub per_page: u32,
+}
+
+fn default_per_page() -> u32 {
+  20
+}
+
+impl Pagination {
+  pub fn offset(&self) -> u64 {
+    self.page as u64 * self.per_page as u64
+  }
+
+  pub fn limit(&self) -> u64 {
+    self.per_page as u64
+  }
+}
@@ -0,0 +1,60 @@
+//! Request ids
+//!
+//! Every API request gets an id, taken from the `X-Request-Id` header if a client or proxy
+//! sent a valid one. It is returned in the same header and in error responses, so reported
+//! errors can be found in logs and traces.
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use axum::{
+  extract::Request,
+  http::{HeaderName, HeaderValue},
+  middleware::Next,
+  response::Response,
+};
+
+const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Longer ids from clients are replaced
+const MAX_LENGTH: usize = 64;
+
+tokio::task_local! {
+  static REQUEST_ID: String;
+}
+
+/// Id of the request being handled, if any
+pub fn current() -> Option<String> {
+  REQUEST_ID.try_with(Clone::clone).ok()
+}
+
+fn is_valid(id: &str) -> bool {
+  !id.is_empty()
+    && id.len() <= MAX_LENGTH
+    && id
+      .chars()
+      .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+fn generate() -> String {
+  let mut bytes = [0; 16];
+  OsRng.fill_bytes(&mut bytes);
+
+  format!("{:032x}", u128::from_be_bytes(bytes))
+}
+
+/// Assign an id to the request. Must wrap all other layers, so their errors have the id
+pub async fn request_id(req: Request, next: Next) -> Response {
+  let id = req
+    .headers()
+    .get(REQUEST_ID_HEADER)
+    .and_then(|id| id.to_str().ok())
+    .filter(|id| is_valid(id))
+    .map_or_else(generate, str::to_string);
+
+  let mut res = REQUEST_ID.scope(id.clone(), next.run(req)).await;
+
+  if let Ok(id) = HeaderValue::from_str(&id) {
+    res.headers_mut().insert(REQUEST_ID_HEADER, id);
+  }
+
+  res
+}
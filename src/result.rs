@@ -1,7 +1,16 @@
+use crate::{
+  request_id,
+  user::limits::{self, Limit},
+};
 use axum::{
-  http::StatusCode,
+  http::{header::RETRY_AFTER, HeaderMap, StatusCode},
   response::{IntoResponse, Response},
+  Json,
 };
+use serde::Serialize;
+use std::collections::BTreeMap;
+use tracing::error;
+use ts_rs::TS;
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
@@ -14,40 +23,372 @@ pub enum Error {
   #[error("Ollama error: {0}")]
   Ollama(#[from] ollama::error::OllamaError),
 
+  #[error("Ollama did not respond in time!")]
+  OllamaTimeout,
+
+  #[error("No Ollama backend is available! Retry later")]
+  OllamaUnavailable,
+
+  #[error("Unknown model {0}!")]
+  UnknownModel(String),
+
   #[error("Redis error: {0}")]
   Redis(#[from] redis::RedisError),
 
   #[error("DB error: {0}")]
   Db(#[from] sqlx::Error),
 
+  #[error("HTTP error: {0}")]
+  Http(#[from] reqwest::Error),
+
+  #[error("JWT error: {0}")]
+  Jwt(#[from] jsonwebtoken::errors::Error),
+
+  #[error("OIDC error: {0}")]
+  Oidc(String),
+
+  #[error("TOTP error: {0}")]
+  Totp(#[from] totp_rs::TotpUrlError),
+
   #[error("Validation error: {0}")]
   Validation(#[from] validator::ValidationErrors),
 
+  #[error("Bad request: {0}")]
+  BadRequest(String),
+
   #[error("Not Found")]
   NotFound,
 
   #[error("Invalid message role!")]
   InvalidRole,
 
+  #[error("Invalid audit action!")]
+  InvalidAuditAction,
+
   #[error("Unauthorized")]
   Unauthorized,
 
+  #[error("Forbidden")]
+  Forbidden,
+
+  #[error("Account disabled!")]
+  AccountDisabled,
+
   #[error("Email already taken!")]
   EmailTaken,
+
+  #[error("Two-factor authentication is already enabled!")]
+  TwoFactorEnabled,
+
+  #[error("Two-factor authentication is not enrolled!")]
+  TwoFactorNotEnrolled,
+
+  #[error("Database shard is unavailable! Retry later")]
+  ShardUnavailable,
+
+  #[error("Redis is unavailable! Retry later")]
+  RedisUnavailable,
+
+  #[error("Migration error: {0}")]
+  Migration(String),
+
+  #[error("Usage: {0}")]
+  Usage(String),
+
+  #[error("Resharding error: {0}")]
+  Reshard(String),
+
+  #[error("User data is being moved! Retry later")]
+  UserMigrating,
+
+  #[error("Too many requests! Retry after {retry_after} seconds")]
+  TooManyRequests { retry_after: u64 },
+
+  #[error("{limit} limit of {max} exceeded! Retry after {retry_after} seconds")]
+  RateLimited {
+    limit: Limit,
+    max: u64,
+    retry_after: u64,
+  },
+}
+
+/// Stable machine readable error codes. Never rename or reuse a code, add a new one instead
+#[derive(TS, Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[ts(export, export_to = "./index.ts")]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+  /// Unexpected server error. Details are only logged
+  Internal,
+  /// Stored data could not be read, e.g. an unknown role
+  InvalidData,
+  Validation,
+  NotFound,
+  UnknownModel,
+  Unauthorized,
+  Forbidden,
+  AccountDisabled,
+  EmailTaken,
+  TwoFactorEnabled,
+  TwoFactorNotEnrolled,
+  /// Login attempts are locked out
+  TooManyRequests,
+  /// A per-user limit is exceeded, see the `X-RateLimit-*` headers
+  RateLimited,
+  ShardUnavailable,
+  RedisUnavailable,
+  UserMigrating,
+  ReshardFailed,
+  OllamaError,
+  OllamaTimeout,
+  OllamaUnavailable,
+  /// An identity provider or other upstream service failed
+  UpstreamError,
+}
+
+/// Problem with a field of the request
+#[derive(TS, Debug, Serialize)]
+#[ts(export, export_to = "./index.ts")]
+pub struct FieldError {
+  /// Validator name, e.g. `length` or `range`
+  code: String,
+  message: Option<String>,
+}
+
+/// Body of all error responses
+#[derive(TS, Debug, Serialize)]
+#[ts(export, export_to = "./index.ts")]
+pub struct ErrorResponse {
+  code: ErrorCode,
+  message: String,
+  /// Errors by field of the request, for [ErrorCode::Validation]
+  details: Option<BTreeMap<String, Vec<FieldError>>>,
+  /// Also returned in the `X-Request-Id` header
+  request_id: Option<String>,
+}
+
+impl Error {
+  fn status_and_code(&self) -> (StatusCode, ErrorCode) {
+    match self {
+      Error::Validation(_) | Error::BadRequest(_) => {
+        (StatusCode::BAD_REQUEST, ErrorCode::Validation)
+      }
+      Error::NotFound => (StatusCode::NOT_FOUND, ErrorCode::NotFound),
+      Error::UnknownModel(_) => (StatusCode::NOT_FOUND, ErrorCode::UnknownModel),
+      Error::Unauthorized | Error::Jwt(_) => (StatusCode::UNAUTHORIZED, ErrorCode::Unauthorized),
+      Error::Forbidden => (StatusCode::FORBIDDEN, ErrorCode::Forbidden),
+      Error::AccountDisabled => (StatusCode::FORBIDDEN, ErrorCode::AccountDisabled),
+      Error::EmailTaken => (StatusCode::CONFLICT, ErrorCode::EmailTaken),
+      Error::TwoFactorEnabled => (StatusCode::CONFLICT, ErrorCode::TwoFactorEnabled),
+      Error::TwoFactorNotEnrolled => (StatusCode::BAD_REQUEST, ErrorCode::TwoFactorNotEnrolled),
+      Error::TooManyRequests { .. } => (StatusCode::TOO_MANY_REQUESTS, ErrorCode::TooManyRequests),
+      Error::RateLimited { .. } => (StatusCode::TOO_MANY_REQUESTS, ErrorCode::RateLimited),
+      Error::ShardUnavailable => (StatusCode::SERVICE_UNAVAILABLE, ErrorCode::ShardUnavailable),
+      // shard became unreachable after the last health check
+      Error::Db(sqlx::Error::PoolTimedOut | sqlx::Error::Io(_)) => {
+        (StatusCode::SERVICE_UNAVAILABLE, ErrorCode::ShardUnavailable)
+      }
+      Error::RedisUnavailable => (StatusCode::SERVICE_UNAVAILABLE, ErrorCode::RedisUnavailable),
+      Error::Redis(e) if e.is_io_error() || e.is_timeout() || e.is_connection_dropped() => {
+        (StatusCode::SERVICE_UNAVAILABLE, ErrorCode::RedisUnavailable)
+      }
+      Error::UserMigrating => (StatusCode::SERVICE_UNAVAILABLE, ErrorCode::UserMigrating),
+      Error::Reshard(_) => (StatusCode::CONFLICT, ErrorCode::ReshardFailed),
+      Error::Ollama(_) => (StatusCode::BAD_GATEWAY, ErrorCode::OllamaError),
+      Error::OllamaTimeout => (StatusCode::GATEWAY_TIMEOUT, ErrorCode::OllamaTimeout),
+      Error::OllamaUnavailable => (
+        StatusCode::SERVICE_UNAVAILABLE,
+        ErrorCode::OllamaUnavailable,
+      ),
+      Error::Http(_) | Error::Oidc(_) => (StatusCode::BAD_GATEWAY, ErrorCode::UpstreamError),
+      Error::InvalidRole | Error::InvalidAuditAction => {
+        (StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::InvalidData)
+      }
+      Error::Io(_)
+      | Error::Serde(_)
+      | Error::Redis(_)
+      | Error::Db(_)
+      | Error::Totp(_)
+      | Error::Migration(_)
+      | Error::Usage(_) => (StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::Internal),
+    }
+  }
+
+  fn details(&self) -> Option<BTreeMap<String, Vec<FieldError>>> {
+    let Error::Validation(errors) = self else {
+      return None;
+    };
+
+    let details = errors
+      .field_errors()
+      .into_iter()
+      .map(|(field, errors)| {
+        let errors = errors
+          .iter()
+          .map(|e| FieldError {
+            code: e.code.to_string(),
+            message: e.message.as_ref().map(|message| message.to_string()),
+          })
+          .collect();
+
+        (field.to_string(), errors)
+      })
+      .collect();
+
+    Some(details)
+  }
+
+  fn headers(&self) -> HeaderMap {
+    match *self {
+      Error::TooManyRequests { retry_after } => {
+        HeaderMap::from_iter([(RETRY_AFTER, retry_after.into())])
+      }
+      Error::RateLimited {
+        limit,
+        max,
+        retry_after,
+      } => limits::exceeded_headers(limit, max, retry_after),
+      _ => HeaderMap::new(),
+    }
+  }
 }
 
 impl IntoResponse for Error {
   fn into_response(self) -> Response {
-    let code = match self {
-      Error::NotFound => StatusCode::NOT_FOUND,
-      Error::EmailTaken => StatusCode::CONFLICT,
-      Error::Unauthorized => StatusCode::UNAUTHORIZED,
-      Error::Validation(_) => StatusCode::BAD_REQUEST,
-      _ => StatusCode::INTERNAL_SERVER_ERROR,
+    let (status, code) = self.status_and_code();
+
+    // internal errors may contain queries, urls and such
+    let message = match (code, &self) {
+      (ErrorCode::Internal | ErrorCode::InvalidData, _) => {
+        error!("{self}");
+        "Internal server error".to_string()
+      }
+      (_, Error::Http(_)) => {
+        error!("{self}");
+        "Upstream service failed".to_string()
+      }
+      _ => self.to_string(),
+    };
+
+    let body = ErrorResponse {
+      code,
+      message,
+      details: self.details(),
+      request_id: request_id::current(),
     };
 
-    (code, self.to_string()).into_response()
+    (status, self.headers(), Json(body)).into_response()
   }
 }
 
 pub type Result<T = ()> = core::result::Result<T, Error>;
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::extract;
+  use axum::{
+    body::{to_bytes, Body},
+    extract::{FromRequest, Request},
+  };
+  use serde_json::Value;
+  use validator::{ValidationError, ValidationErrors};
+
+  async fn body(res: Response) -> Value {
+    let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+
+    serde_json::from_slice(&bytes).unwrap()
+  }
+
+  #[test]
+  fn status_and_code() {
+    let cases = [
+      (Error::NotFound, StatusCode::NOT_FOUND, ErrorCode::NotFound),
+      (
+        Error::BadRequest("bad".into()),
+        StatusCode::BAD_REQUEST,
+        ErrorCode::Validation,
+      ),
+      (
+        Error::Validation(ValidationErrors::new()),
+        StatusCode::BAD_REQUEST,
+        ErrorCode::Validation,
+      ),
+      (
+        Error::EmailTaken,
+        StatusCode::CONFLICT,
+        ErrorCode::EmailTaken,
+      ),
+      (
+        Error::RateLimited {
+          limit: Limit::RequestsPerMinute,
+          max: 1,
+          retry_after: 1,
+        },
+        StatusCode::TOO_MANY_REQUESTS,
+        ErrorCode::RateLimited,
+      ),
+      (
+        Error::Db(sqlx::Error::PoolTimedOut),
+        StatusCode::SERVICE_UNAVAILABLE,
+        ErrorCode::ShardUnavailable,
+      ),
+      (
+        Error::Db(sqlx::Error::RowNotFound),
+        StatusCode::INTERNAL_SERVER_ERROR,
+        ErrorCode::Internal,
+      ),
+      (
+        Error::InvalidRole,
+        StatusCode::INTERNAL_SERVER_ERROR,
+        ErrorCode::InvalidData,
+      ),
+      (
+        Error::Oidc("denied".into()),
+        StatusCode::BAD_GATEWAY,
+        ErrorCode::UpstreamError,
+      ),
+    ];
+
+    for (error, status, code) in cases {
+      assert_eq!(error.status_and_code(), (status, code), "{error}");
+    }
+  }
+
+  #[tokio::test]
+  async fn internal_details_are_hidden() {
+    let res = Error::Migration("ALTER TABLE secret".into()).into_response();
+
+    assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+    let body = body(res).await;
+    assert_eq!(body["code"], "internal");
+    assert_eq!(body["message"], "Internal server error");
+  }
+
+  #[tokio::test]
+  async fn validation_details() {
+    let mut errors = ValidationErrors::new();
+    errors.add("text", ValidationError::new("length"));
+
+    let body = body(Error::Validation(errors).into_response()).await;
+
+    assert_eq!(body["code"], "validation");
+    assert_eq!(body["details"]["text"][0]["code"], "length");
+  }
+
+  #[tokio::test]
+  async fn json_rejection() {
+    let req = Request::post("/")
+      .header("content-type", "application/json")
+      .body(Body::from("{"))
+      .unwrap();
+
+    let Err(e) = extract::Json::<Value>::from_request(req, &()).await else {
+      panic!("malformed JSON must be rejected");
+    };
+
+    let res = e.into_response();
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(body(res).await["code"], "validation");
+  }
+}
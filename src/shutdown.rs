@@ -0,0 +1,105 @@
+//! Graceful shutdown
+//!
+//! On SIGTERM or SIGINT the server stops accepting connections and waits for in-flight
+//! requests, e.g. generations, for at most [crate::config::ServerConfig::shutdown_timeout].
+//! A second signal stops waiting. Afterwards deferred cache invalidations are flushed and
+//! connections are closed, see [cleanup].
+
+use crate::{db, otel};
+use std::{
+  sync::atomic::{AtomicBool, Ordering},
+  time::Duration,
+};
+use tokio::{signal, sync::watch};
+use tracing::{info, warn};
+
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+/// Whether the first shutdown signal was received
+pub fn started() -> bool {
+  STARTED.load(Ordering::Relaxed)
+}
+
+/// Resolves on SIGTERM or SIGINT
+async fn signal() {
+  let interrupt = async {
+    if let Err(e) = signal::ctrl_c().await {
+      warn!("Failed to listen for SIGINT: {e}");
+      std::future::pending::<()>().await;
+    }
+  };
+
+  #[cfg(unix)]
+  let terminate = async {
+    match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+      Ok(mut terminate) => {
+        terminate.recv().await;
+      }
+      Err(e) => {
+        warn!("Failed to listen for SIGTERM: {e}");
+        std::future::pending::<()>().await;
+      }
+    }
+  };
+
+  #[cfg(not(unix))]
+  let terminate = std::future::pending::<()>();
+
+  tokio::select! {
+    _ = interrupt => {},
+    _ = terminate => {},
+  }
+}
+
+/// Ties the drain deadline to the first shutdown signal
+pub struct Shutdown {
+  signaled: watch::Sender<bool>,
+}
+
+impl Shutdown {
+  pub fn new() -> Self {
+    Self {
+      signaled: watch::Sender::new(false),
+    }
+  }
+
+  /// Pass to `with_graceful_shutdown`. Resolves on the first signal
+  pub fn signal(&self) -> impl std::future::Future<Output = ()> + Send + 'static {
+    let signaled = self.signaled.clone();
+
+    async move {
+      signal().await;
+
+      info!("Shutting down, waiting for in-flight requests");
+      STARTED.store(true, Ordering::Relaxed);
+      signaled.send_replace(true);
+    }
+  }
+
+  /// Resolves when in-flight requests took longer than `timeout` after the first signal,
+  /// or on a second signal
+  pub fn deadline(&self, timeout: Duration) -> impl std::future::Future<Output = ()> + Send {
+    let mut signaled = self.signaled.subscribe();
+
+    async move {
+      if signaled.wait_for(|signaled| *signaled).await.is_err() {
+        return std::future::pending().await;
+      }
+
+      tokio::select! {
+        _ = tokio::time::sleep(timeout) => {
+          warn!("In-flight requests did not finish in {}s, aborting them", timeout.as_secs());
+        }
+        _ = signal() => warn!("Signaled again, aborting in-flight requests"),
+      }
+    }
+  }
+}
+
+/// Release resources once the server stopped
+pub async fn cleanup() {
+  db::close().await;
+  otel::shutdown();
+
+  info!("Shutdown complete");
+}
@@ -1,12 +1,9 @@
+use crate::config::{config, Config};
 use jsonwebtoken::{DecodingKey, EncodingKey};
-use ollama::Ollama;
 use std::sync::OnceLock;
 use tracing::{info, instrument};
 
 pub struct AppState {
-  /// Ollama connection
-  pub ollama: Ollama,
-
   /// JWT encoding key
   pub jwt_encode: EncodingKey,
 
@@ -18,28 +15,17 @@ static APP_STATE: OnceLock<AppState> = OnceLock::new();
 
 /// Initialize the app state
 ///
-/// - Creates **Ollama** connection
 /// - Creates **JWT** encoding & decoding keys
 #[instrument(name = "AppState::init", skip_all)]
-pub fn init(ollama_url: String, jwt_secret: String) {
-  let state = AppState {
-    ollama: {
-      let port_pos = ollama_url
-        .rfind(':')
-        .expect("OLLAMA_URL must contain a port!");
-
-      let port = ollama_url[port_pos + 1..]
-        .parse()
-        .expect("OLLAMA_URL port must be a number!");
-
-      Ollama::new(&ollama_url[..port_pos], port)
-    },
+pub fn init(config: &Config) {
+  let jwt_secret = &config.auth.jwt_secret;
 
+  let state = AppState {
     jwt_encode: EncodingKey::from_secret(jwt_secret.as_bytes()),
     jwt_decode: DecodingKey::from_secret(jwt_secret.as_bytes()),
   };
 
-  info!("Ollama, Redis, Postgres connections established");
+  info!("App state initialized");
 
   if APP_STATE.set(state).is_err() {
     panic!("Failed to initialize the app state!");
@@ -52,10 +38,6 @@ fn get() -> &'static AppState {
     .expect("For some magical reason, the app state is not initialized!")
 }
 
-pub fn ollama() -> &'static Ollama {
-  &get().ollama
-}
-
 pub fn jwt_encode() -> &'static EncodingKey {
   &get().jwt_encode
 }
@@ -63,3 +45,13 @@ pub fn jwt_encode() -> &'static EncodingKey {
 pub fn jwt_decode() -> &'static DecodingKey {
   &get().jwt_decode
 }
+
+/// Whether the email is granted the admin role when signing up with a verified email,
+/// i.e. through OIDC. Other users are promoted with `robo admin promote <email>`
+pub fn is_admin_email(email: &str) -> bool {
+  config()
+    .auth
+    .admin_emails
+    .iter()
+    .any(|admin| admin.eq_ignore_ascii_case(email))
+}
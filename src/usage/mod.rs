@@ -0,0 +1,58 @@
+//! Token usage accounting
+//!
+//! Every generation appends a row with its token counts and durations to the
+//! `token_usage` table on the shard of the user. Rows outlive the chats they belong to.
+
+mod routes;
+pub mod schemas;
+
+use crate::{result::Result, user::auth};
+use axum::{middleware::from_fn, routing::get, Router};
+use ollama::generation::chat::ChatMessageFinalResponseData;
+use schemas::TokenUsageIden;
+use sea_query::{PostgresQueryBuilder, Query};
+use sqlx::{query, PgConnection};
+
+/// Record usage of a generation. Meant to run in the transaction saving its messages
+pub async fn record(
+  conn: &mut PgConnection,
+  user_id: i32,
+  message_id: i32,
+  model: &str,
+  usage: &ChatMessageFinalResponseData,
+) -> Result {
+  let insert_usage_query = Query::insert()
+    .into_table(TokenUsageIden::Table)
+    .columns([
+      TokenUsageIden::UserId,
+      TokenUsageIden::MessageId,
+      TokenUsageIden::Model,
+      TokenUsageIden::PromptTokens,
+      TokenUsageIden::CompletionTokens,
+      TokenUsageIden::TotalDuration,
+      TokenUsageIden::PromptEvalDuration,
+      TokenUsageIden::EvalDuration,
+    ])
+    .values_panic([
+      user_id.into(),
+      message_id.into(),
+      model.into(),
+      (usage.prompt_eval_count as i32).into(),
+      (usage.eval_count as i32).into(),
+      (usage.total_duration as i64).into(),
+      (usage.prompt_eval_duration as i64).into(),
+      (usage.eval_duration as i64).into(),
+    ])
+    .to_string(PostgresQueryBuilder);
+
+  query(&insert_usage_query).execute(conn).await?;
+
+  Ok(())
+}
+
+pub fn usage_router() -> Router {
+  Router::new()
+    .route("/me/usage", get(routes::get_my_usage))
+    .route("/admin/usage", get(routes::get_usage))
+    .layer(from_fn(auth::auth_middleware))
+}
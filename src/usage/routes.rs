@@ -0,0 +1,217 @@
+//! Token usage API routes
+
+use super::schemas::{DailyUsage, ModelUsage, TokenUsageIden, UsageTotals};
+use crate::{
+  db::{for_each_shard, postgres_read},
+  extract::{Json, Query as QueryParams},
+  result::Result,
+  user::auth::{Admin, Auth},
+};
+use chrono::{Days, NaiveDate, Utc};
+use sea_query::{Alias, Expr, Order, PostgresQueryBuilder, Query, SelectStatement, SimpleExpr};
+use serde::{Deserialize, Serialize};
+use sqlx::query_as;
+use std::collections::BTreeMap;
+use tracing::instrument;
+use ts_rs::TS;
+use validator::{ValidationError, ValidationErrors};
+
+/// Days covered when the range is not given
+const DEFAULT_RANGE_DAYS: u64 = 30;
+
+/// Longest range that can be requested
+const MAX_RANGE_DAYS: u64 = 366;
+
+const DAY: &str = r#"("created_at" AT TIME ZONE 'UTC')::date"#;
+
+#[derive(TS, Debug, Deserialize)]
+#[ts(export, export_to = "./index.ts")]
+pub struct UsageRange {
+  /// First UTC day, inclusive. Defaults to 30 days before `to`
+  #[ts(optional)]
+  from: Option<NaiveDate>,
+  /// Last UTC day, inclusive. Defaults to today
+  #[ts(optional)]
+  to: Option<NaiveDate>,
+}
+
+impl UsageRange {
+  fn bounds(&self) -> Result<(NaiveDate, NaiveDate)> {
+    let to = self.to.unwrap_or_else(|| Utc::now().date_naive());
+
+    let from = self
+      .from
+      .or_else(|| to.checked_sub_days(Days::new(DEFAULT_RANGE_DAYS - 1)))
+      .unwrap_or(to);
+
+    let days = (to - from).num_days() + 1;
+
+    if days < 1 || days as u64 > MAX_RANGE_DAYS {
+      let mut errors = ValidationErrors::new();
+      errors.add("from", ValidationError::new("range"));
+
+      return Err(errors.into());
+    }
+
+    Ok((from, to))
+  }
+
+  /// Usage rows created within the range
+  fn condition(&self) -> Result<SimpleExpr> {
+    let (from, to) = self.bounds()?;
+
+    let start_of = |day: NaiveDate| day.and_time(Default::default()).and_utc().to_rfc3339();
+    let end = to.checked_add_days(Days::new(1)).unwrap_or(to);
+
+    Ok(
+      Expr::col(TokenUsageIden::CreatedAt)
+        .gte(start_of(from))
+        .and(Expr::col(TokenUsageIden::CreatedAt).lt(start_of(end))),
+    )
+  }
+}
+
+#[derive(TS, Debug, Serialize)]
+#[ts(export, export_to = "./index.ts")]
+pub struct UsageReport {
+  total: UsageTotals,
+  /// Days without generations are omitted
+  days: Vec<DailyUsage>,
+  /// Most used first
+  models: Vec<ModelUsage>,
+}
+
+impl UsageReport {
+  fn new(days: Vec<DailyUsage>, mut models: Vec<ModelUsage>) -> Self {
+    let mut total = UsageTotals::default();
+
+    for day in &days {
+      total.add(&day.totals);
+    }
+
+    models.sort_by(|a, b| {
+      let tokens = |usage: &ModelUsage| usage.totals.prompt_tokens + usage.totals.completion_tokens;
+
+      tokens(b)
+        .cmp(&tokens(a))
+        .then_with(|| a.model.cmp(&b.model))
+    });
+
+    Self {
+      total,
+      days,
+      models,
+    }
+  }
+}
+
+fn select_totals(condition: SimpleExpr) -> SelectStatement {
+  Query::select()
+    .from(TokenUsageIden::Table)
+    .expr_as(Expr::cust("COUNT(*)"), Alias::new("generations"))
+    .expr_as(
+      Expr::cust(r#"SUM("prompt_tokens")::bigint"#),
+      Alias::new("prompt_tokens"),
+    )
+    .expr_as(
+      Expr::cust(r#"SUM("completion_tokens")::bigint"#),
+      Alias::new("completion_tokens"),
+    )
+    .expr_as(
+      Expr::cust(r#"(SUM("prompt_eval_duration" + "eval_duration") / 1000000)::bigint"#),
+      Alias::new("duration_ms"),
+    )
+    .and_where(condition)
+    .to_owned()
+}
+
+/// Queries aggregating usage by day and by model
+fn usage_queries(condition: SimpleExpr) -> (String, String) {
+  let days_query = select_totals(condition.clone())
+    .expr_as(Expr::cust(DAY), Alias::new("day"))
+    .add_group_by([Expr::cust(DAY)])
+    .order_by_expr(Expr::cust(DAY), Order::Asc)
+    .to_string(PostgresQueryBuilder);
+
+  let models_query = select_totals(condition)
+    .column(TokenUsageIden::Model)
+    .group_by_col(TokenUsageIden::Model)
+    .to_string(PostgresQueryBuilder);
+
+  (days_query, models_query)
+}
+
+async fn user_usage(user_id: i32, range: &UsageRange) -> Result<UsageReport> {
+  let condition = range
+    .condition()?
+    .and(Expr::col(TokenUsageIden::UserId).eq(user_id));
+
+  let (days_query, models_query) = usage_queries(condition);
+
+  let db = postgres_read(user_id).await?;
+
+  let days = query_as(&days_query).fetch_all(db).await?;
+  let models = query_as(&models_query).fetch_all(db).await?;
+
+  Ok(UsageReport::new(days, models))
+}
+
+/// get own token usage by day and by model
+#[instrument(name = "usage::get_my_usage")]
+pub async fn get_my_usage(
+  Auth(user_id): Auth,
+  QueryParams(range): QueryParams<UsageRange>,
+) -> Result<Json<UsageReport>> {
+  Ok(Json(user_usage(user_id, &range).await?))
+}
+
+#[derive(TS, Debug, Deserialize)]
+#[ts(export, export_to = "./index.ts")]
+pub struct UsageFilter {
+  /// Only usage of this user. All users if absent
+  #[ts(optional)]
+  user_id: Option<i32>,
+}
+
+/// get token usage of a user or of all users across all shards
+#[instrument(name = "usage::get_usage")]
+pub async fn get_usage(
+  Admin(_): Admin,
+  QueryParams(filter): QueryParams<UsageFilter>,
+  QueryParams(range): QueryParams<UsageRange>,
+) -> Result<Json<UsageReport>> {
+  if let Some(user_id) = filter.user_id {
+    return Ok(Json(user_usage(user_id, &range).await?));
+  }
+
+  let (days_query, models_query) = usage_queries(range.condition()?);
+
+  let shard_days =
+    for_each_shard(|db| query_as::<_, DailyUsage>(&days_query).fetch_all(db)).await?;
+  let shard_models =
+    for_each_shard(|db| query_as::<_, ModelUsage>(&models_query).fetch_all(db)).await?;
+
+  // the same day or model may appear on every shard
+  let mut days = BTreeMap::<NaiveDate, UsageTotals>::new();
+  let mut models = BTreeMap::<String, UsageTotals>::new();
+
+  for usage in shard_days.into_iter().flatten() {
+    days.entry(usage.day).or_default().add(&usage.totals);
+  }
+
+  for usage in shard_models.into_iter().flatten() {
+    models.entry(usage.model).or_default().add(&usage.totals);
+  }
+
+  let days = days
+    .into_iter()
+    .map(|(day, totals)| DailyUsage { day, totals })
+    .collect();
+
+  let models = models
+    .into_iter()
+    .map(|(model, totals)| ModelUsage { model, totals })
+    .collect();
+
+  Ok(Json(UsageReport::new(days, models)))
+}
@@ -0,0 +1,152 @@
+//! Token usage DB schemas
+
+use crate::{chat::schemas::MessageIden, user::schemas::UserIden};
+use chrono::NaiveDate;
+use sea_query::{
+  ColumnDef, Expr, ForeignKey, ForeignKeyAction, Iden, Index, PostgresQueryBuilder, Table,
+};
+use serde::Serialize;
+use sqlx::FromRow;
+use ts_rs::TS;
+
+/// One row per generation. Stored on the shard of the user
+#[derive(Iden)]
+pub enum TokenUsageIden {
+  #[iden = "token_usage"]
+  Table,
+  Id,
+  UserId,
+  /// AI message of the generation. Cleared when the chat is deleted, usage stays
+  MessageId,
+  Model,
+  PromptTokens,
+  CompletionTokens,
+  /// Durations are in nanoseconds, as reported by Ollama
+  TotalDuration,
+  PromptEvalDuration,
+  EvalDuration,
+  CreatedAt,
+}
+
+/// Sums over a group of generations
+#[derive(TS, Debug, Clone, Copy, Default, Serialize, FromRow)]
+#[ts(export, export_to = "./index.ts")]
+pub struct UsageTotals {
+  #[ts(type = "number")]
+  pub generations: i64,
+  #[ts(type = "number")]
+  pub prompt_tokens: i64,
+  #[ts(type = "number")]
+  pub completion_tokens: i64,
+  /// Time spent evaluating prompts and generating responses
+  #[ts(type = "number")]
+  pub duration_ms: i64,
+}
+
+impl UsageTotals {
+  pub fn add(&mut self, other: &Self) {
+    self.generations += other.generations;
+    self.prompt_tokens += other.prompt_tokens;
+    self.completion_tokens += other.completion_tokens;
+    self.duration_ms += other.duration_ms;
+  }
+}
+
+#[derive(TS, Debug, Serialize, FromRow)]
+#[ts(export, export_to = "./index.ts")]
+pub struct DailyUsage {
+  /// UTC day
+  pub day: NaiveDate,
+  #[serde(flatten)]
+  #[sqlx(flatten)]
+  #[ts(flatten)]
+  pub totals: UsageTotals,
+}
+
+#[derive(TS, Debug, Serialize, FromRow)]
+#[ts(export, export_to = "./index.ts")]
+pub struct ModelUsage {
+  pub model: String,
+  #[serde(flatten)]
+  #[sqlx(flatten)]
+  #[ts(flatten)]
+  pub totals: UsageTotals,
+}
+
+/// Statements of the `create_usage_tables` migration. See [crate::db::migrations]
+pub fn create_tables() -> Vec<String> {
+  let token_usage_table = Table::create()
+    .table(TokenUsageIden::Table)
+    .if_not_exists()
+    .col(
+      ColumnDef::new(TokenUsageIden::Id)
+        .integer()
+        .not_null()
+        .auto_increment()
+        .primary_key(),
+    )
+    .col(ColumnDef::new(TokenUsageIden::UserId).integer().not_null())
+    .col(ColumnDef::new(TokenUsageIden::MessageId).integer().null())
+    .col(ColumnDef::new(TokenUsageIden::Model).string().not_null())
+    .col(
+      ColumnDef::new(TokenUsageIden::PromptTokens)
+        .integer()
+        .not_null(),
+    )
+    .col(
+      ColumnDef::new(TokenUsageIden::CompletionTokens)
+        .integer()
+        .not_null(),
+    )
+    .col(
+      ColumnDef::new(TokenUsageIden::TotalDuration)
+        .big_integer()
+        .not_null(),
+    )
+    .col(
+      ColumnDef::new(TokenUsageIden::PromptEvalDuration)
+        .big_integer()
+        .not_null(),
+    )
+    .col(
+      ColumnDef::new(TokenUsageIden::EvalDuration)
+        .big_integer()
+        .not_null(),
+    )
+    .col(
+      ColumnDef::new(TokenUsageIden::CreatedAt)
+        .timestamp_with_time_zone()
+        .not_null()
+        .default(Expr::current_timestamp()),
+    )
+    .foreign_key(
+      ForeignKey::create()
+        .from(TokenUsageIden::Table, TokenUsageIden::UserId)
+        .to(UserIden::Table, UserIden::Id)
+        .on_delete(ForeignKeyAction::Cascade),
+    )
+    .foreign_key(
+      ForeignKey::create()
+        .from(TokenUsageIden::Table, TokenUsageIden::MessageId)
+        .to(MessageIden::Table, MessageIden::Id)
+        .on_delete(ForeignKeyAction::SetNull),
+    )
+    .to_string(PostgresQueryBuilder);
+
+  let token_usage_index = Index::create()
+    .if_not_exists()
+    .name("token_usage_user_id_created_at_idx")
+    .table(TokenUsageIden::Table)
+    .col(TokenUsageIden::UserId)
+    .col(TokenUsageIden::CreatedAt)
+    .to_string(PostgresQueryBuilder);
+
+  vec![token_usage_table, token_usage_index]
+}
+
+pub fn drop_tables() -> Vec<String> {
+  vec![Table::drop()
+    .table(TokenUsageIden::Table)
+    .if_exists()
+    .to_string(PostgresQueryBuilder)]
+}
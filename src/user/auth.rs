@@ -1,6 +1,11 @@
 use crate::{
-  jwt::validate_jwt,
+  db::{cache, postgres},
+  jwt::{validate_jwt, Claims},
   result::{Error, Result},
+  user::{
+    limits,
+    schemas::{UserIden, UserRole, UserTokensIden},
+  },
 };
 use axum::{
   extract::{FromRequestParts, Request},
@@ -8,7 +13,97 @@ use axum::{
   middleware::Next,
   response::Response,
 };
-use tracing::error;
+use chrono::{DateTime, Utc};
+use sea_query::{Expr, PostgresQueryBuilder, Query};
+use serde::{Deserialize, Serialize};
+use sqlx::{query, query_as, FromRow};
+use std::fmt::Display;
+use tracing::{error, instrument};
+
+/// Role and disabled flag are re-read from the DB at least this often. Tokens outlive
+/// role changes, so the token role is never trusted
+const ACCOUNT_CACHE_TTL: u64 = 30;
+
+/// Access related state of a user, checked on every authenticated request
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, FromRow)]
+pub struct Account {
+  #[sqlx(try_from = "i16")]
+  pub role: UserRole,
+  pub disabled: bool,
+  pub tokens_revoked_at: Option<DateTime<Utc>>,
+}
+
+impl Account {
+  fn is_revoked(&self, claims: &Claims) -> bool {
+    self
+      .tokens_revoked_at
+      .is_some_and(|revoked_at| (claims.iat as i64) < revoked_at.timestamp())
+  }
+}
+
+fn account_cache_key(user_id: i32) -> String {
+  format!("{user_id}:account")
+}
+
+/// Redis key marking a user whose data is being moved to another shard
+pub fn migrating_cache_key(user_id: impl Display) -> String {
+  format!("{user_id}:migrating")
+}
+
+/// Account of the user, `None` if the user was deleted. Read from the primary, so
+/// changes made by admins apply as soon as the cached value expires or is invalidated
+#[instrument(name = "auth::account")]
+async fn account(user_id: i32) -> Result<Option<Account>> {
+  let cache_key = account_cache_key(user_id);
+
+  if let Some(account) = cache::get::<Account>(&cache_key).await {
+    return Ok(Some(account));
+  }
+
+  let account_query = Query::select()
+    .from(UserIden::Table)
+    .columns([UserIden::Role, UserIden::Disabled])
+    .column(UserTokensIden::TokensRevokedAt)
+    .and_where(Expr::col(UserIden::Id).eq(user_id))
+    .to_string(PostgresQueryBuilder);
+
+  let db = postgres(user_id)?;
+
+  let account: Option<Account> = query_as(&account_query).fetch_optional(db).await?;
+
+  if let Some(account) = account {
+    cache::set(&cache_key, account, ACCOUNT_CACHE_TTL).await;
+  }
+
+  Ok(account)
+}
+
+/// Forget the cached account after its role or disabled flag was changed
+pub async fn invalidate_account(user_id: i32) {
+  cache::invalidate(&account_cache_key(user_id)).await;
+}
+
+/// Reject all tokens of the user issued until now. Fails with [Error::NotFound] if the
+/// user does not exist
+#[instrument(name = "auth::revoke_tokens")]
+pub async fn revoke_tokens(user_id: i32) -> Result {
+  let revoke_query = Query::update()
+    .table(UserTokensIden::Table)
+    .value(UserTokensIden::TokensRevokedAt, Expr::current_timestamp())
+    .and_where(Expr::col(UserTokensIden::Id).eq(user_id))
+    .to_string(PostgresQueryBuilder);
+
+  let db = postgres(user_id)?;
+  let res = query(&revoke_query).execute(db).await?;
+
+  if res.rows_affected() == 0 {
+    return Err(Error::NotFound);
+  }
+
+  invalidate_account(user_id).await;
+
+  Ok(())
+}
 
 pub async fn auth_middleware(mut req: Request, next: Next) -> Result<Response> {
   let Some(auth_header) = req.headers().get("Authorization") else {
@@ -24,18 +119,58 @@ pub async fn auth_middleware(mut req: Request, next: Next) -> Result<Response> {
     return Err(Error::Unauthorized)?;
   };
 
-  let id = match validate_jwt(token) {
-    Ok(id) => id,
+  let claims = match validate_jwt(token) {
+    Ok(claims) => claims,
     _ => return Err(Error::Unauthorized)?,
   };
 
-  req.extensions_mut().insert(id);
+  // tokens issued before the account was disabled or demoted are still valid JWTs
+  let Some(account) = account(claims.id).await? else {
+    return Err(Error::Unauthorized);
+  };
+
+  if account.is_revoked(&claims) {
+    return Err(Error::Unauthorized);
+  }
+
+  if account.disabled {
+    return Err(Error::AccountDisabled);
+  }
+
+  // writes during the move could be lost
+  if cache::get::<bool>(&migrating_cache_key(claims.id))
+    .await
+    .unwrap_or_default()
+  {
+    return Err(Error::UserMigrating);
+  }
+
+  let limits = limits::get(claims.id).await?;
+  let rate = limits::check_rate(claims.id, &limits).await?;
 
-  let response = next.run(req).await;
+  req.extensions_mut().insert(claims);
+  req.extensions_mut().insert(account);
+  req.extensions_mut().insert(limits);
+
+  let mut response = next.run(req).await;
+
+  if let Some(rate) = rate {
+    rate.add_headers(response.headers_mut());
+  }
 
   Ok(response)
 }
 
+fn claims_from_parts(req: &Parts) -> Result<&Claims> {
+  let Some(claims) = req.extensions.get::<Claims>() else {
+    error!("Attempted to extract user id from request extensions, but none was found. Most likely the auth middleware was not used on this route");
+
+    return Err(Error::Unauthorized);
+  };
+
+  Ok(claims)
+}
+
 /// Extracts the user id from JWT token. Must only be used inside auth middleware
 #[derive(Debug)]
 pub struct Auth(pub i32);
@@ -44,12 +179,31 @@ impl<S: Send + Sync> FromRequestParts<S> for Auth {
   type Rejection = Error;
 
   async fn from_request_parts(req: &mut Parts, _: &S) -> Result<Self> {
-    let Some(user_id) = req.extensions.get::<i32>() else {
-      error!("Attempted to extract user id from request extensions, but none was found. Most likely the auth middleware was not used on this route");
+    claims_from_parts(req).map(|claims| Self(claims.id))
+  }
+}
+
+/// Extracts the user id from JWT token and ensures the user is an admin. The role is
+/// checked against the DB, not the token. Must only be used inside auth middleware
+#[derive(Debug)]
+pub struct Admin(pub i32);
+
+impl<S: Send + Sync> FromRequestParts<S> for Admin {
+  type Rejection = Error;
+
+  async fn from_request_parts(req: &mut Parts, _: &S) -> Result<Self> {
+    let claims = claims_from_parts(req)?;
+
+    let Some(account) = req.extensions.get::<Account>() else {
+      error!("Account is missing from request extensions. Most likely the auth middleware was not used on this route");
 
       return Err(Error::Unauthorized);
     };
 
-    Ok(Self(*user_id))
+    if account.role != UserRole::Admin {
+      return Err(Error::Forbidden);
+    }
+
+    Ok(Self(claims.id))
   }
 }
@@ -0,0 +1,228 @@
+//! Global email directory
+//!
+//! Maps every email to the user id and the shard storing the user. Lives on the
+//! [metadata] shard, so looking up a user by email touches only two databases
+//! instead of all shards. The primary key on email makes registration race-free.
+//!
+//! Emails are case insensitive, entries are stored [normalize]d.
+
+use crate::{
+  db::{metadata, METADATA_SHARD},
+  result::{Error, Result},
+};
+use futures::future::BoxFuture;
+use sea_query::{ColumnDef, Expr, Iden, PostgresQueryBuilder, Query, Table};
+use sqlx::{query, query_as, query_scalar, PgConnection};
+use tracing::{info, warn};
+
+/// Rows inserted by a single backfill statement
+const BACKFILL_BATCH: usize = 1000;
+
+#[derive(Iden)]
+enum EmailDirectoryIden {
+  #[iden = "email_directory"]
+  Table,
+  Email,
+  UserId,
+  Shard,
+}
+
+/// Statements creating `email_directory` table. Only applied to the metadata shard
+pub fn create_tables() -> Vec<String> {
+  let email_directory_table = Table::create()
+    .table(EmailDirectoryIden::Table)
+    .if_not_exists()
+    .col(
+      ColumnDef::new(EmailDirectoryIden::Email)
+        .string()
+        .not_null()
+        .primary_key(),
+    )
+    .col(
+      ColumnDef::new(EmailDirectoryIden::UserId)
+        .integer()
+        .not_null()
+        .unique_key(),
+    )
+    .col(
+      ColumnDef::new(EmailDirectoryIden::Shard)
+        .small_integer()
+        .not_null(),
+    )
+    .to_string(PostgresQueryBuilder);
+
+  vec![email_directory_table]
+}
+
+pub fn drop_tables() -> Vec<String> {
+  vec![Table::drop()
+    .table(EmailDirectoryIden::Table)
+    .if_exists()
+    .to_string(PostgresQueryBuilder)]
+}
+
+/// Data migration adding users of the shard to the directory. Registrations used to
+/// race, so the same email may exist on several shards, only the first one is added.
+///
+/// Until a shard is migrated, emails of its users may be registered again.
+pub fn backfill(conn: &mut PgConnection, index: usize) -> BoxFuture<'_, sqlx::Result<()>> {
+  Box::pin(async move {
+    let users: Vec<(i32, String)> = query_as("SELECT \"id\", \"email\" FROM \"user\"")
+      .fetch_all(&mut *conn)
+      .await?;
+
+    if users.is_empty() {
+      return Ok(());
+    }
+
+    info!(
+      "Adding {} users of shard {} to email directory",
+      users.len(),
+      index + 1
+    );
+
+    // the directory may have been created earlier in the same transaction
+    let mut metadata_conn;
+    let directory_conn = match index == METADATA_SHARD {
+      true => conn,
+      false => {
+        metadata_conn = metadata().acquire().await?;
+        &mut *metadata_conn
+      }
+    };
+
+    for batch in users.chunks(BACKFILL_BATCH) {
+      let (user_ids, emails): (Vec<i32>, Vec<&str>) = batch
+        .iter()
+        .map(|(id, email)| (*id, email.as_str()))
+        .unzip();
+
+      // the migration may be retried, entries of the same user are not duplicates
+      query(
+        "INSERT INTO \"email_directory\" (\"email\", \"user_id\", \"shard\") \
+         SELECT *, $3::smallint FROM unnest($1::text[], $2::int[]) \
+         ON CONFLICT DO NOTHING",
+      )
+      .bind(&emails)
+      .bind(&user_ids)
+      .bind(index as i16)
+      .execute(&mut *directory_conn)
+      .await?;
+
+      let duplicates: i64 = query_scalar(
+        "SELECT COUNT(*) FROM unnest($1::text[], $2::int[]) AS \"batch\" (\"email\", \"user_id\") \
+         JOIN \"email_directory\" USING (\"email\") \
+         WHERE \"email_directory\".\"user_id\" <> \"batch\".\"user_id\"",
+      )
+      .bind(&emails)
+      .bind(&user_ids)
+      .fetch_one(&mut *directory_conn)
+      .await?;
+
+      if duplicates > 0 {
+        warn!(
+          "{duplicates} duplicate emails on shard {} were not added to email directory",
+          index + 1
+        );
+      }
+    }
+
+    Ok(())
+  })
+}
+
+/// Data migration lowercasing emails of the directory, see [normalize]. Entries which
+/// differ only in case are left as is, until an admin resolves them
+pub fn lowercase_emails(conn: &mut PgConnection, _: usize) -> BoxFuture<'_, sqlx::Result<()>> {
+  Box::pin(async move {
+    query(
+      "UPDATE \"email_directory\" SET \"email\" = lower(\"email\") \
+       WHERE \"email\" <> lower(\"email\") AND NOT EXISTS ( \
+         SELECT 1 FROM \"email_directory\" AS \"other\" \
+         WHERE lower(\"other\".\"email\") = lower(\"email_directory\".\"email\") \
+           AND \"other\".\"email\" <> \"email_directory\".\"email\" \
+       )",
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    let conflicts: i64 =
+      query_scalar("SELECT COUNT(*) FROM \"email_directory\" WHERE \"email\" <> lower(\"email\")")
+        .fetch_one(&mut *conn)
+        .await?;
+
+    if conflicts > 0 {
+      warn!("{conflicts} emails differ only in case from other emails and were not lowercased");
+    }
+
+    Ok(())
+  })
+}
+
+/// Emails are compared case insensitively
+pub fn normalize(email: &str) -> String {
+  email.trim().to_lowercase()
+}
+
+/// Find user id and shard index by email
+pub async fn lookup(email: &str) -> Result<Option<(i32, usize)>> {
+  let lookup_query = Query::select()
+    .from(EmailDirectoryIden::Table)
+    .columns([EmailDirectoryIden::UserId, EmailDirectoryIden::Shard])
+    .and_where(Expr::col(EmailDirectoryIden::Email).eq(normalize(email)))
+    .to_string(PostgresQueryBuilder);
+
+  let entry = query_as::<_, (i32, i16)>(&lookup_query)
+    .fetch_optional(metadata())
+    .await?;
+
+  Ok(entry.map(|(user_id, shard)| (user_id, shard as usize)))
+}
+
+/// Claim the email for the user. Fails with [Error::EmailTaken] if someone else has it
+pub async fn reserve(email: &str, user_id: i32, shard: usize) -> Result {
+  let reserve_query = Query::insert()
+    .into_table(EmailDirectoryIden::Table)
+    .columns([
+      EmailDirectoryIden::Email,
+      EmailDirectoryIden::UserId,
+      EmailDirectoryIden::Shard,
+    ])
+    .values_panic([
+      normalize(email).into(),
+      user_id.into(),
+      (shard as i16).into(),
+    ])
+    .to_string(PostgresQueryBuilder);
+
+  match query(&reserve_query).execute(metadata()).await {
+    Ok(_) => Ok(()),
+    Err(sqlx::Error::Database(e)) if e.is_unique_violation() => Err(Error::EmailTaken),
+    Err(e) => Err(e.into()),
+  }
+}
+
+/// Give up the email reserved by [reserve], e.g. if the user could not be inserted
+pub async fn release(email: &str) -> Result {
+  let release_query = Query::delete()
+    .from_table(EmailDirectoryIden::Table)
+    .and_where(Expr::col(EmailDirectoryIden::Email).eq(normalize(email)))
+    .to_string(PostgresQueryBuilder);
+
+  query(&release_query).execute(metadata()).await?;
+
+  Ok(())
+}
+
+/// Point the user's entry to another shard. Runs in the caller's metadata transaction
+pub async fn set_shard(user_id: i32, shard: usize, conn: &mut PgConnection) -> Result {
+  let set_shard_query = Query::update()
+    .table(EmailDirectoryIden::Table)
+    .value(EmailDirectoryIden::Shard, shard as i16)
+    .and_where(Expr::col(EmailDirectoryIden::UserId).eq(user_id))
+    .to_string(PostgresQueryBuilder);
+
+  query(&set_shard_query).execute(conn).await?;
+
+  Ok(())
+}
@@ -0,0 +1,501 @@
+//! Per-user rate limits and generation quotas
+//!
+//! Counters are kept in Redis:
+//! - requests in the current minute, checked by [super::auth::auth_middleware]
+//! - generations in progress, a sorted set scored by their deadlines, see
+//!   [start_generation]
+//! - prompt and response tokens used today (UTC), see [check_tokens] and [record_tokens]
+//!
+//! Defaults come from [LimitsConfig], admins may override them per user. Limits are not
+//! enforced while Redis is down.
+
+use super::schemas::UserLimitsIden;
+use crate::{
+  config::{config, LimitsConfig},
+  db::{cache, health, postgres, redis},
+  result::{Error, Result},
+};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use axum::http::{header::RETRY_AFTER, HeaderMap, HeaderName, HeaderValue};
+use chrono::{Days, Utc};
+use redis::{aio::ConnectionLike, AsyncCommands, RedisResult};
+use sea_query::{Expr, PostgresQueryBuilder, Query};
+use serde::{Deserialize, Serialize};
+use sqlx::{query_as, FromRow};
+use std::{
+  fmt::{self, Display},
+  time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::{error, instrument};
+use ts_rs::TS;
+use validator::Validate;
+
+/// Overrides are re-read from the DB at least this often
+const OVERRIDES_CACHE_TTL: u64 = 60;
+
+/// Slots of generations which were never released, e.g. because the process was killed
+/// or Redis was unreachable, expire after this many seconds on top of the Ollama timeout
+const GENERATIONS_GRACE: u64 = 60;
+
+/// Clients may retry this many seconds after hitting the concurrent generations limit
+const GENERATIONS_RETRY_AFTER: u64 = 1;
+
+const TOKENS_KEY_TTL: u64 = 2 * 24 * 60 * 60;
+
+const LIMIT_HEADER: HeaderName = HeaderName::from_static("x-ratelimit-limit");
+const REMAINING_HEADER: HeaderName = HeaderName::from_static("x-ratelimit-remaining");
+const RESET_HEADER: HeaderName = HeaderName::from_static("x-ratelimit-reset");
+const SCOPE_HEADER: HeaderName = HeaderName::from_static("x-ratelimit-scope");
+
+fn overrides_cache_key(user_id: i32) -> String {
+  format!("{user_id}:limits")
+}
+
+fn requests_key(user_id: i32, minute: u64) -> String {
+  format!("{user_id}:requests:{minute}")
+}
+
+fn generations_key(user_id: i32) -> String {
+  format!("{user_id}:generation_slots")
+}
+
+fn tokens_key(user_id: i32) -> String {
+  format!("{user_id}:tokens:{}", Utc::now().format("%Y-%m-%d"))
+}
+
+/// Minute of the requests limit window at `now` and seconds until the next one starts
+fn rate_window(now: u64) -> (u64, u64) {
+  (now / 60, 60 - now % 60)
+}
+
+fn secs_until_midnight() -> u64 {
+  let now = Utc::now();
+
+  let midnight = now
+    .date_naive()
+    .checked_add_days(Days::new(1))
+    .and_then(|tomorrow| tomorrow.and_hms_opt(0, 0, 0))
+    .map(|midnight| midnight.and_utc());
+
+  midnight.map_or(0, |midnight| (midnight - now).num_seconds().max(1) as u64)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Limit {
+  RequestsPerMinute,
+  ConcurrentGenerations,
+  DailyTokens,
+}
+
+impl Limit {
+  /// Value of the `X-RateLimit-Scope` header
+  fn scope(self) -> &'static str {
+    match self {
+      Limit::RequestsPerMinute => "requests",
+      Limit::ConcurrentGenerations => "generations",
+      Limit::DailyTokens => "tokens",
+    }
+  }
+}
+
+impl Display for Limit {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.write_str(match self {
+      Limit::RequestsPerMinute => "Requests per minute",
+      Limit::ConcurrentGenerations => "Concurrent generations",
+      Limit::DailyTokens => "Daily token",
+    })
+  }
+}
+
+/// Headers of a 429 response, see [Error::RateLimited]
+pub fn exceeded_headers(limit: Limit, max: u64, retry_after: u64) -> HeaderMap {
+  let mut headers = HeaderMap::new();
+
+  headers.insert(RETRY_AFTER, retry_after.into());
+  headers.insert(LIMIT_HEADER, max.into());
+  headers.insert(REMAINING_HEADER, 0.into());
+  headers.insert(RESET_HEADER, retry_after.into());
+  headers.insert(SCOPE_HEADER, HeaderValue::from_static(limit.scope()));
+
+  headers
+}
+
+/// Effective limits of a user. 0 means unlimited
+#[derive(TS, Debug, Clone, Copy, Serialize)]
+#[ts(export, export_to = "./index.ts")]
+pub struct Limits {
+  pub requests_per_minute: u32,
+  pub concurrent_generations: u32,
+  #[ts(type = "number")]
+  pub daily_tokens: u64,
+}
+
+/// Per-user limits set by admins. Absent values fall back to the defaults from config
+#[derive(TS, Debug, Clone, Copy, Default, Serialize, Deserialize, FromRow, Validate)]
+#[ts(export, export_to = "./index.ts")]
+pub struct LimitOverrides {
+  #[ts(optional)]
+  #[validate(range(min = 0))]
+  #[sqlx(rename = "limit_requests_per_minute")]
+  pub requests_per_minute: Option<i32>,
+  #[ts(optional)]
+  #[validate(range(min = 0))]
+  #[sqlx(rename = "limit_concurrent_generations")]
+  pub concurrent_generations: Option<i32>,
+  #[ts(optional, type = "number")]
+  #[validate(range(min = 0))]
+  #[sqlx(rename = "limit_daily_tokens")]
+  pub daily_tokens: Option<i64>,
+}
+
+impl LimitOverrides {
+  pub fn apply(self, defaults: &LimitsConfig) -> Limits {
+    Limits {
+      requests_per_minute: self
+        .requests_per_minute
+        .map_or(defaults.requests_per_minute, |max| max as u32),
+      concurrent_generations: self
+        .concurrent_generations
+        .map_or(defaults.concurrent_generations, |max| max as u32),
+      daily_tokens: self
+        .daily_tokens
+        .map_or(defaults.daily_tokens, |max| max as u64),
+    }
+  }
+}
+
+/// Load overrides of the user from the primary, bypassing the cache. Overrides are
+/// written by admins without pinning the user, see [cache]
+pub async fn load_overrides(user_id: i32) -> Result<Option<LimitOverrides>> {
+  let overrides_query = Query::select()
+    .from(UserLimitsIden::Table)
+    .columns([
+      UserLimitsIden::LimitRequestsPerMinute,
+      UserLimitsIden::LimitConcurrentGenerations,
+      UserLimitsIden::LimitDailyTokens,
+    ])
+    .and_where(Expr::col(UserLimitsIden::Id).eq(user_id))
+    .to_string(PostgresQueryBuilder);
+
+  let db = postgres(user_id)?;
+
+  Ok(query_as(&overrides_query).fetch_optional(db).await?)
+}
+
+/// Forget cached overrides after they were changed
+pub async fn invalidate_overrides(user_id: i32) {
+  cache::invalidate(&overrides_cache_key(user_id)).await;
+}
+
+/// Effective limits of the user
+#[instrument(name = "limits::get")]
+pub async fn get(user_id: i32) -> Result<Limits> {
+  let cache_key = overrides_cache_key(user_id);
+
+  let overrides = match cache::get::<LimitOverrides>(&cache_key).await {
+    Some(overrides) => overrides,
+    None => {
+      let overrides = load_overrides(user_id).await?.unwrap_or_default();
+      cache::set(&cache_key, overrides, OVERRIDES_CACHE_TTL).await;
+
+      overrides
+    }
+  };
+
+  Ok(overrides.apply(&config().limits))
+}
+
+/// State of the requests per minute limit after counting a request
+pub struct RateStatus {
+  max: u32,
+  remaining: u32,
+  reset: u64,
+}
+
+impl RateStatus {
+  pub fn add_headers(&self, headers: &mut HeaderMap) {
+    headers.insert(LIMIT_HEADER, self.max.into());
+    headers.insert(REMAINING_HEADER, self.remaining.into());
+    headers.insert(RESET_HEADER, self.reset.into());
+    headers.insert(
+      SCOPE_HEADER,
+      HeaderValue::from_static(Limit::RequestsPerMinute.scope()),
+    );
+  }
+}
+
+/// Count a request of the user. `None` if the limit is not enforced
+#[instrument(name = "limits::check_rate", skip(limits))]
+pub async fn check_rate(user_id: i32, limits: &Limits) -> Result<Option<RateStatus>> {
+  let max = limits.requests_per_minute;
+
+  if max == 0 {
+    return Ok(None);
+  }
+
+  let Some(mut redis) = redis() else {
+    return Ok(None);
+  };
+
+  let now = SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .unwrap_or_default()
+    .as_secs();
+
+  let (minute, reset) = rate_window(now);
+
+  let requests = match count_request(&mut redis, &requests_key(user_id, minute)).await {
+    Ok(requests) => requests,
+    Err(e) => {
+      error!("{e}");
+      health::redis_failed();
+
+      return Ok(None);
+    }
+  };
+
+  if requests > max {
+    return Err(Error::RateLimited {
+      limit: Limit::RequestsPerMinute,
+      max: max.into(),
+      retry_after: reset,
+    });
+  }
+
+  Ok(Some(RateStatus {
+    max,
+    remaining: max - requests,
+    reset,
+  }))
+}
+
+/// Count a request in the window stored at `key`. Returns requests in the window
+async fn count_request(redis: &mut impl ConnectionLike, key: &str) -> RedisResult<u32> {
+  let (requests,) = redis::pipe()
+    .atomic()
+    .incr(key, 1)
+    .expire(key, 60)
+    .ignore()
+    .query_async(redis)
+    .await?;
+
+  Ok(requests)
+}
+
+/// Slot of a generation in progress. Released when dropped, otherwise it expires at its
+/// deadline
+pub struct Generation {
+  key: String,
+  slot: String,
+}
+
+impl Drop for Generation {
+  fn drop(&mut self) {
+    let key = std::mem::take(&mut self.key);
+    let slot = std::mem::take(&mut self.slot);
+
+    tokio::spawn(async move {
+      let Some(mut redis) = redis() else {
+        return;
+      };
+
+      if let Err(e) = redis.zrem::<_, _, ()>(&key, &slot).await {
+        error!("{e}");
+      }
+    });
+  }
+}
+
+/// Add `slot` to the set at `key`, expiring `ttl` seconds after `now`. Expired slots are
+/// pruned before counting. Returns slots in use, including the new one
+async fn take_slot(
+  redis: &mut impl ConnectionLike,
+  key: &str,
+  slot: &str,
+  now: u64,
+  ttl: u64,
+) -> RedisResult<u32> {
+  let (generations,) = redis::pipe()
+    .atomic()
+    .zrembyscore(key, "-inf", now)
+    .ignore()
+    .zadd(key, slot, now + ttl)
+    .ignore()
+    .zcard(key)
+    .expire(key, ttl as i64)
+    .ignore()
+    .query_async(redis)
+    .await?;
+
+  Ok(generations)
+}
+
+/// Take a generation slot of the user. `None` if the limit is not enforced
+#[instrument(name = "limits::start_generation", skip(limits))]
+pub async fn start_generation(user_id: i32, limits: &Limits) -> Result<Option<Generation>> {
+  let max = limits.concurrent_generations;
+
+  if max == 0 {
+    return Ok(None);
+  }
+
+  let Some(mut redis) = redis() else {
+    return Ok(None);
+  };
+
+  let key = generations_key(user_id);
+  let slot = format!("{:016x}", OsRng.next_u64());
+  let ttl = config().ollama.timeout + GENERATIONS_GRACE;
+
+  let now = SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .unwrap_or_default()
+    .as_secs();
+
+  let generations = match take_slot(&mut redis, &key, &slot, now, ttl).await {
+    Ok(generations) => generations,
+    Err(e) => {
+      error!("{e}");
+      health::redis_failed();
+
+      return Ok(None);
+    }
+  };
+
+  // released by the drop
+  let generation = Generation { key, slot };
+
+  if generations > max {
+    return Err(Error::RateLimited {
+      limit: Limit::ConcurrentGenerations,
+      max: max.into(),
+      retry_after: GENERATIONS_RETRY_AFTER,
+    });
+  }
+
+  Ok(Some(generation))
+}
+
+/// Fails if the user has used up the daily tokens. The last generation may exceed the
+/// quota, since its length is not known upfront
+#[instrument(name = "limits::check_tokens", skip(limits))]
+pub async fn check_tokens(user_id: i32, limits: &Limits) -> Result {
+  let max = limits.daily_tokens;
+
+  if max == 0 {
+    return Ok(());
+  }
+
+  let Some(mut redis) = redis() else {
+    return Ok(());
+  };
+
+  let used = match redis.get::<_, Option<u64>>(tokens_key(user_id)).await {
+    Ok(used) => used.unwrap_or_default(),
+    Err(e) => {
+      error!("{e}");
+      health::redis_failed();
+
+      return Ok(());
+    }
+  };
+
+  if used >= max {
+    return Err(Error::RateLimited {
+      limit: Limit::DailyTokens,
+      max,
+      retry_after: secs_until_midnight(),
+    });
+  }
+
+  Ok(())
+}
+
+/// Count prompt and response tokens of a finished generation
+#[instrument(name = "limits::record_tokens")]
+pub async fn record_tokens(user_id: i32, tokens: u64) {
+  let Some(mut redis) = redis() else {
+    return;
+  };
+
+  let key = tokens_key(user_id);
+
+  let res = redis::pipe()
+    .atomic()
+    .incr(&key, tokens)
+    .ignore()
+    .expire(&key, TOKENS_KEY_TTL as i64)
+    .ignore()
+    .query_async::<()>(&mut redis)
+    .await;
+
+  if let Err(e) = res {
+    error!("{e}");
+    health::redis_failed();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use redis::aio::MultiplexedConnection;
+  use std::env::var;
+
+  async fn test_redis() -> MultiplexedConnection {
+    redis::Client::open(var("TEST_REDIS_URL").unwrap())
+      .unwrap()
+      .get_multiplexed_async_connection()
+      .await
+      .unwrap()
+  }
+
+  fn test_key(name: &str) -> String {
+    format!("limits_test:{name}:{:016x}", OsRng.next_u64())
+  }
+
+  #[test]
+  fn rate_window_resets_every_minute() {
+    assert_eq!(rate_window(0), (0, 60));
+    assert_eq!(rate_window(59), (0, 1));
+    assert_eq!(rate_window(60), (1, 60));
+    assert_eq!(rate_window(125), (2, 55));
+  }
+
+  #[tokio::test]
+  #[ignore = "needs Redis, set TEST_REDIS_URL"]
+  async fn requests_are_counted_per_window() {
+    let mut redis = test_redis().await;
+    let (key, next_key) = (test_key("requests"), test_key("requests"));
+
+    assert_eq!(count_request(&mut redis, &key).await.unwrap(), 1);
+    assert_eq!(count_request(&mut redis, &key).await.unwrap(), 2);
+    assert_eq!(count_request(&mut redis, &next_key).await.unwrap(), 1);
+
+    // windows are removed once they are over
+    let ttl: i64 = redis.ttl(&key).await.unwrap();
+    assert!((1..=60).contains(&ttl), "{ttl}");
+  }
+
+  #[tokio::test]
+  #[ignore = "needs Redis, set TEST_REDIS_URL"]
+  async fn leaked_slots_expire() {
+    let mut redis = test_redis().await;
+    let key = test_key("slots");
+
+    assert_eq!(take_slot(&mut redis, &key, "a", 1000, 10).await.unwrap(), 1);
+    assert_eq!(take_slot(&mut redis, &key, "b", 1005, 10).await.unwrap(), 2);
+
+    // "a" was never released and expired at 1010
+    assert_eq!(take_slot(&mut redis, &key, "c", 1010, 10).await.unwrap(), 2);
+
+    let slots: Vec<String> = redis.zrange(&key, 0, -1).await.unwrap();
+    assert_eq!(slots, ["b", "c"]);
+
+    // released slots are removed right away, see Generation
+    let _: () = redis.zrem(&key, "b").await.unwrap();
+    assert_eq!(take_slot(&mut redis, &key, "d", 1011, 10).await.unwrap(), 2);
+
+    let _: () = redis.del(&key).await.unwrap();
+  }
+}
@@ -0,0 +1,151 @@
+//! Login brute-force protection
+//!
+//! Failed login attempts are counted in Redis per email and per IP. Once a counter
+//! exceeds its threshold, the email or IP gets locked out for an exponentially growing
+//! period of time.
+//!
+//! Behind a reverse proxy all clients share the proxy address, so its network must be
+//! listed in [crate::config::ServerConfig::trusted_proxies] for per-IP counters to work.
+
+use crate::{
+  db::redis,
+  result::{Error, Result},
+};
+use redis::{AsyncCommands, RedisResult};
+use std::{
+  net::IpAddr,
+  time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::{error, instrument, warn};
+
+/// Failures are forgotten after this period without new failures
+const FAILURE_WINDOW: Duration = Duration::from_secs(15 * 60);
+
+const MAX_EMAIL_FAILURES: u32 = 5;
+const MAX_IP_FAILURES: u32 = 20;
+
+const BASE_LOCKOUT: Duration = Duration::from_secs(30);
+const MAX_LOCKOUT: Duration = Duration::from_secs(60 * 60);
+
+fn email_failures_key(email: &str) -> String {
+  format!("login_failures:email:{}", email.to_lowercase())
+}
+
+fn ip_failures_key(ip: IpAddr) -> String {
+  format!("login_failures:ip:{ip}")
+}
+
+fn lock_key(failures_key: &str) -> String {
+  format!("{failures_key}:lock")
+}
+
+/// Lockout duration for the given failures count. Doubles with every failure over the limit
+fn lockout_duration(failures: u32, max_failures: u32) -> Option<Duration> {
+  let over_limit = failures.checked_sub(max_failures)?;
+
+  let lockout = BASE_LOCKOUT.saturating_mul(2u32.saturating_pow(over_limit));
+
+  Some(lockout.min(MAX_LOCKOUT))
+}
+
+/// Returns [Error::TooManyRequests] if either email or IP is locked out.
+///
+/// Redis errors are logged and ignored, login must keep working without Redis.
+#[instrument(name = "lockout::check")]
+pub async fn check(email: &str, ip: IpAddr) -> Result {
+  let Some(mut redis) = redis() else {
+    return Ok(());
+  };
+
+  for key in [email_failures_key(email), ip_failures_key(ip)] {
+    // -2 if key does not exist, -1 if it has no expiry
+    match redis.ttl::<_, i64>(lock_key(&key)).await {
+      Ok(retry_after) if retry_after > 0 => {
+        return Err(Error::TooManyRequests {
+          retry_after: retry_after as u64,
+        })
+      }
+      Ok(_) => {}
+      Err(e) => error!("{e}"),
+    }
+  }
+
+  Ok(())
+}
+
+/// Counts a failed login attempt, locking out email or IP if they exceeded their limits
+#[instrument(name = "lockout::register_failure")]
+pub async fn register_failure(email: &str, ip: IpAddr) {
+  let Some(mut redis) = redis() else {
+    return;
+  };
+
+  let counters = [
+    (email_failures_key(email), MAX_EMAIL_FAILURES),
+    (ip_failures_key(ip), MAX_IP_FAILURES),
+  ];
+
+  for (key, max_failures) in counters {
+    let failures: RedisResult<(u32,)> = redis::pipe()
+      .atomic()
+      .incr(&key, 1)
+      .expire(&key, FAILURE_WINDOW.as_secs() as i64)
+      .ignore()
+      .query_async(&mut redis)
+      .await;
+
+    let failures = match failures {
+      Ok((failures,)) => failures,
+      Err(e) => {
+        error!("{e}");
+        continue;
+      }
+    };
+
+    let Some(lockout) = lockout_duration(failures, max_failures) else {
+      continue;
+    };
+
+    // keep failures around for as long as the lockout lasts, so the next one grows
+    let res = redis::pipe()
+      .atomic()
+      .set_ex(lock_key(&key), 1, lockout.as_secs())
+      .ignore()
+      .expire(&key, (FAILURE_WINDOW + lockout).as_secs() as i64)
+      .ignore()
+      .query_async::<()>(&mut redis)
+      .await;
+
+    if let Err(e) = res {
+      error!("{e}");
+      continue;
+    }
+
+    let timestamp = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .unwrap_or_default()
+      .as_secs();
+
+    warn!(
+      email,
+      %ip,
+      timestamp,
+      failures,
+      lockout_secs = lockout.as_secs(),
+      "login lockout on {key}"
+    );
+  }
+}
+
+/// Resets email failures counter after a successful login.
+/// IP failures are kept since a single IP may be guessing passwords for many accounts
+#[instrument(name = "lockout::register_success")]
+pub async fn register_success(email: &str) {
+  let Some(mut redis) = redis() else {
+    return;
+  };
+
+  if let Err(e) = redis.del::<_, ()>(email_failures_key(email)).await {
+    error!("{e}");
+  }
+}
@@ -1,14 +1,36 @@
 //! User API
 
 pub mod auth;
+pub mod directory;
+pub mod limits;
+mod lockout;
+pub mod oidc;
 mod routes;
 pub mod schemas;
+mod two_factor;
 
-use axum::{routing::post, Router};
-use routes::{create_user, login_user};
+use axum::{
+  middleware::from_fn,
+  routing::{get, post},
+  Router,
+};
+use routes::{change_password, create_user, login_user, revoke_tokens};
 
 pub fn user_router() -> Router {
   Router::new()
     .route("/register", post(create_user))
     .route("/login", post(login_user))
+    .route("/2fa/verify", post(two_factor::verify))
+    .route("/auth/oidc", get(oidc::get_providers))
+    .route("/auth/oidc/{provider}/authorize", get(oidc::authorize))
+    .route("/auth/oidc/{provider}/callback", post(oidc::callback))
+    .merge(
+      Router::new()
+        .route("/2fa/enroll", post(two_factor::enroll))
+        .route("/2fa/confirm", post(two_factor::confirm))
+        .route("/2fa/disable", post(two_factor::disable))
+        .route("/me/password", post(change_password))
+        .route("/me/tokens/revoke", post(revoke_tokens))
+        .layer(from_fn(auth::auth_middleware)),
+    )
 }
@@ -1,17 +1,22 @@
 //! User API routes
 
 use crate::{
-  db::{fetch_add_max_uid, postgres},
-  jwt::create_jwt,
+  audit::{schemas::AuditAction, AuditEntry, ClientInfo},
+  db::{health::shard_up, next_user_id, postgres, shard, shard_index, shards},
+  extract::Json,
+  jwt::{create_2fa_jwt, create_jwt},
   result::{Error, Result},
-  user::schemas::{User, UserIden},
+  user::{
+    auth::{self, Auth},
+    directory, lockout,
+    schemas::{User, UserIden, UserRole, UserTokensIden},
+  },
 };
 use argon2::{
   password_hash::{rand_core::OsRng, PasswordHasher, SaltString},
   Argon2, PasswordHash, PasswordVerifier,
 };
-use axum::Json;
-use sea_query::{Expr, PostgresQueryBuilder, Query};
+use sea_query::{Expr, PostgresQueryBuilder, Query, SelectStatement};
 use serde::{Deserialize, Serialize};
 use sqlx::{query, query_as};
 use tracing::instrument;
@@ -21,9 +26,10 @@ use validator::Validate;
 #[derive(TS, Debug, Serialize)]
 #[ts(export, export_to = "./index.ts")]
 pub struct PublicUser {
-  id: i32,
-  name: String,
-  email: String,
+  pub id: i32,
+  pub name: String,
+  pub email: String,
+  pub role: UserRole,
 }
 
 #[derive(TS, Debug, Serialize)]
@@ -33,6 +39,37 @@ pub struct AuthUser {
   public_user: PublicUser,
 }
 
+impl From<User> for AuthUser {
+  /// Issues a token for the user
+  fn from(user: User) -> Self {
+    Self {
+      token: create_jwt(user.id, user.role),
+      public_user: PublicUser {
+        id: user.id,
+        name: user.name,
+        email: user.email,
+        role: user.role,
+      },
+    }
+  }
+}
+
+/// Returned by login instead of [AuthUser] for accounts with 2FA enabled
+#[derive(TS, Debug, Serialize)]
+#[ts(export, export_to = "./index.ts")]
+pub struct TwoFactorPending {
+  /// Short lived token to exchange for [AuthUser] along with TOTP or recovery code
+  two_factor_token: String,
+}
+
+#[derive(TS, Debug, Serialize)]
+#[ts(export, export_to = "./index.ts")]
+#[serde(untagged)]
+pub enum LoginResponse {
+  Authenticated(AuthUser),
+  TwoFactorPending(TwoFactorPending),
+}
+
 #[derive(TS, Debug, Deserialize, Validate)]
 #[ts(export, export_to = "./index.ts")]
 pub struct RegisterRequest {
@@ -44,41 +81,94 @@ pub struct RegisterRequest {
   password: String,
 }
 
-/// create new user
-#[instrument(name = "users::create_user")]
-pub async fn create_user(Json(new_user): Json<RegisterRequest>) -> Result<Json<AuthUser>> {
-  new_user.validate()?;
+fn hash_password(password: &str) -> String {
+  Argon2::default()
+    .hash_password(password.as_bytes(), &SaltString::generate(&mut OsRng))
+    .unwrap()
+    .to_string()
+}
+
+/// Users without a password, e.g. created by OIDC login, never pass
+fn verify_password(password_hash: &str, password: &str) -> bool {
+  let Ok(parsed_hash) = PasswordHash::new(password_hash) else {
+    return false;
+  };
+
+  Argon2::default()
+    .verify_password(password.as_bytes(), &parsed_hash)
+    .is_ok()
+}
 
-  // check if email is taken
-  let find_by_email_query = Query::select()
+fn select_user() -> SelectStatement {
+  Query::select()
     .from(UserIden::Table)
-    .columns([UserIden::Email])
-    .and_where(Expr::col(UserIden::Email).eq(new_user.email.clone()))
+    .columns([
+      UserIden::Id,
+      UserIden::Name,
+      UserIden::Email,
+      UserIden::Password,
+      UserIden::Role,
+      UserIden::Disabled,
+      UserIden::TotpSecret,
+      UserIden::TotpEnabled,
+    ])
+    .to_owned()
+}
+
+pub(super) async fn find_user_by_id(user_id: i32) -> Result<User> {
+  let find_by_id_query = select_user()
+    .and_where(Expr::col(UserIden::Id).eq(user_id))
     .to_string(PostgresQueryBuilder);
 
-  // query all shards for email
-  let (cols1, cols2) = tokio::join!(
-    query(&find_by_email_query).fetch_optional(postgres(1)),
-    query(&find_by_email_query).fetch_optional(postgres(2)),
-  );
+  let db = postgres(user_id)?;
 
-  // if at least one shard failed, we can't be sure if the email is taken
-  if cols1?.is_some() || cols2?.is_some() {
-    return Err(Error::EmailTaken); // TODO better errors
+  query_as(&find_by_id_query)
+    .fetch_optional(db)
+    .await?
+    .ok_or(Error::NotFound)
+}
+
+/// find user by email. emails are unique across all shards
+pub(super) async fn find_user_by_email(email: &str) -> Result<Option<User>> {
+  let Some((user_id, shard_index)) = directory::lookup(email).await? else {
+    return Ok(None);
+  };
+
+  let find_by_id_query = select_user()
+    .and_where(Expr::col(UserIden::Id).eq(user_id))
+    .to_string(PostgresQueryBuilder);
+
+  let db = shard(shard_index)?;
+
+  let user = query_as(&find_by_id_query).fetch_optional(db).await?;
+
+  Ok(user)
+}
+
+/// insert new user allocating a new id. fails with [Error::EmailTaken] if the email is taken
+pub(super) async fn insert_user(
+  name: String,
+  email: &str,
+  password_hash: String,
+  role: UserRole,
+) -> Result<User> {
+  let email = directory::normalize(email);
+  let mut next_uid = next_user_id().await?;
+
+  // skip ids of unavailable shards, so registration keeps working during a shard outage
+  for _ in 1..shards().len() {
+    if shard_up(shard_index(next_uid)) {
+      break;
+    }
+
+    next_uid = next_user_id().await?;
   }
 
-  // hash password
-  let password_hash = Argon2::default()
-    .hash_password(
-      new_user.password.as_bytes(),
-      &SaltString::generate(&mut OsRng),
-    )
-    .unwrap()
-    .to_string();
+  let db = postgres(next_uid)?;
 
-  let next_uid = fetch_add_max_uid();
+  // concurrent registrations with the same email are resolved by the directory
+  directory::reserve(&email, next_uid, shard_index(next_uid)).await?;
 
-  // insert new user
   let insert_user_query = Query::insert()
     .into_table(UserIden::Table)
     .columns([
@@ -86,29 +176,76 @@ pub async fn create_user(Json(new_user): Json<RegisterRequest>) -> Result<Json<A
       UserIden::Name,
       UserIden::Email,
       UserIden::Password,
+      UserIden::Role,
     ])
     .values_panic([
       next_uid.into(),
-      new_user.name.clone().into(),
-      new_user.email.clone().into(),
-      password_hash.into(),
+      name.clone().into(),
+      email.clone().into(),
+      password_hash.clone().into(),
+      role.into(),
     ])
     .to_string(PostgresQueryBuilder);
 
-  query(&insert_user_query)
-    .execute(postgres(next_uid))
-    .await?;
+  if let Err(e) = query(&insert_user_query).execute(db).await {
+    directory::release(&email).await?;
 
-  // return user with token
-  let token = create_jwt(next_uid);
+    return Err(e.into());
+  }
 
-  let public_user = PublicUser {
+  Ok(User {
     id: next_uid,
-    name: new_user.name,
-    email: new_user.email,
-  };
+    name,
+    email,
+    password: password_hash,
+    role,
+    disabled: false,
+    totp_secret: None,
+    totp_enabled: false,
+  })
+}
 
-  Ok(Json(AuthUser { token, public_user }))
+/// Issues a token, unless the user has to pass 2FA first
+pub(super) fn login_response(user: User) -> LoginResponse {
+  if user.totp_enabled {
+    let two_factor_token = create_2fa_jwt(user.id);
+
+    return LoginResponse::TwoFactorPending(TwoFactorPending { two_factor_token });
+  }
+
+  LoginResponse::Authenticated(user.into())
+}
+
+/// create new user
+#[instrument(name = "users::create_user", skip(new_user))]
+pub async fn create_user(
+  client: ClientInfo,
+  Json(new_user): Json<RegisterRequest>,
+) -> Result<Json<AuthUser>> {
+  new_user.validate()?;
+
+  // fail fast before hashing. insert_user still rejects taken emails
+  if directory::lookup(&new_user.email).await?.is_some() {
+    return Err(Error::EmailTaken); // TODO better errors
+  }
+
+  let password_hash = hash_password(&new_user.password);
+
+  // the email is not verified, so it never grants the admin role, see is_admin_email
+  let user = insert_user(
+    new_user.name,
+    &new_user.email,
+    password_hash,
+    UserRole::User,
+  )
+  .await?;
+
+  AuditEntry::new(user.id, AuditAction::Register)
+    .record(&client)
+    .await;
+
+  // return user with token
+  Ok(Json(user.into()))
 }
 
 #[derive(TS, Debug, Deserialize)]
@@ -119,51 +256,122 @@ pub struct LoginRequest {
 }
 
 /// login user
-#[instrument(name = "users::login_user")]
-pub async fn login_user(Json(login_request): Json<LoginRequest>) -> Result<Json<AuthUser>> {
-  // get user by email
-  let find_by_email_query = Query::select()
-    .from(UserIden::Table)
-    .columns([
-      UserIden::Id,
-      UserIden::Name,
-      UserIden::Email,
-      UserIden::Password,
-    ])
-    .and_where(Expr::col(UserIden::Email).eq(login_request.email.clone()))
-    .to_string(PostgresQueryBuilder);
+#[instrument(name = "users::login_user", skip(login_request))]
+pub async fn login_user(
+  client: ClientInfo,
+  Json(login_request): Json<LoginRequest>,
+) -> Result<Json<LoginResponse>> {
+  let ip = client.ip;
 
-  // query all shards for email
-  let (user1, user2) = tokio::join!(
-    query_as::<_, User>(&find_by_email_query).fetch_optional(postgres(1)),
-    query_as::<_, User>(&find_by_email_query).fetch_optional(postgres(2)),
-  );
+  // bail out before doing any expensive work
+  lockout::check(&login_request.email, ip).await?;
 
-  let Some(user) = user1?.or(user2?) else {
-    return Err(Error::Unauthorized);
-  };
+  // get user by email
+  let Some(user) = find_user_by_email(&login_request.email).await? else {
+    lockout::register_failure(&login_request.email, ip).await;
 
-  // verify password
-  let Ok(parsed_hash) = PasswordHash::new(&user.password) else {
     return Err(Error::Unauthorized);
   };
 
-  let is_valid = Argon2::default()
-    .verify_password(login_request.password.as_bytes(), &parsed_hash)
-    .is_ok();
+  if !verify_password(&user.password, &login_request.password) {
+    lockout::register_failure(&login_request.email, ip).await;
+
+    AuditEntry::new(user.id, AuditAction::LoginFailed)
+      .details("wrong password")
+      .record(&client)
+      .await;
 
-  if !is_valid {
     return Err(Error::Unauthorized);
   }
 
+  // only tell that the account is disabled to someone who knows the password
+  if user.disabled {
+    AuditEntry::new(user.id, AuditAction::LoginFailed)
+      .details("account disabled")
+      .record(&client)
+      .await;
+
+    return Err(Error::AccountDisabled);
+  }
+
+  // failures are reset only after the second factor is verified.
+  // otherwise knowing the password would allow to guess codes indefinitely
+  if !user.totp_enabled {
+    lockout::register_success(&login_request.email).await;
+
+    AuditEntry::new(user.id, AuditAction::Login)
+      .record(&client)
+      .await;
+  }
+
   // return user with token
-  let token = create_jwt(user.id);
+  Ok(Json(login_response(user)))
+}
 
-  let public_user = PublicUser {
-    id: user.id,
-    name: user.name,
-    email: user.email,
-  };
+#[derive(TS, Debug, Deserialize, Validate)]
+#[ts(export, export_to = "./index.ts")]
+pub struct ChangePasswordRequest {
+  current_password: String,
+  #[validate(length(min = 6, max = 255))]
+  new_password: String,
+}
+
+/// change own password. signs out all other sessions and returns a new token
+#[instrument(name = "users::change_password", skip(req))]
+pub async fn change_password(
+  Auth(user_id): Auth,
+  client: ClientInfo,
+  Json(req): Json<ChangePasswordRequest>,
+) -> Result<Json<AuthUser>> {
+  req.validate()?;
+
+  let mut user = find_user_by_id(user_id).await?;
+
+  // a stolen token must not allow guessing the password
+  lockout::check(&user.email, client.ip).await?;
+
+  if !verify_password(&user.password, &req.current_password) {
+    lockout::register_failure(&user.email, client.ip).await;
+
+    AuditEntry::new(user.id, AuditAction::LoginFailed)
+      .details("wrong password on password change")
+      .record(&client)
+      .await;
+
+    return Err(Error::Unauthorized);
+  }
+
+  user.password = hash_password(&req.new_password);
+
+  let change_password_query = Query::update()
+    .table(UserIden::Table)
+    .value(UserIden::Password, user.password.clone())
+    .value(UserTokensIden::TokensRevokedAt, Expr::current_timestamp())
+    .and_where(Expr::col(UserIden::Id).eq(user_id))
+    .to_string(PostgresQueryBuilder);
+
+  let db = postgres(user_id)?;
+
+  query(&change_password_query).execute(db).await?;
+
+  auth::invalidate_account(user_id).await;
+
+  AuditEntry::new(user_id, AuditAction::PasswordChanged)
+    .record(&client)
+    .await;
+
+  // issued after the revocation, so it stays valid
+  Ok(Json(user.into()))
+}
+
+/// sign out everywhere. all tokens issued until now stop working
+#[instrument(name = "users::revoke_tokens")]
+pub async fn revoke_tokens(Auth(user_id): Auth, client: ClientInfo) -> Result<()> {
+  auth::revoke_tokens(user_id).await?;
+
+  AuditEntry::new(user_id, AuditAction::TokensRevoked)
+    .record(&client)
+    .await;
 
-  Ok(Json(AuthUser { token, public_user }))
+  Ok(())
 }
@@ -1,26 +1,108 @@
 //! User DB schemas
 
-use crate::result::Result;
-use sea_query::{enum_def, ColumnDef, PostgresQueryBuilder, Table};
+use crate::result::{Error, Result};
+use sea_query::{enum_def, ColumnDef, ForeignKey, Iden, PostgresQueryBuilder, Table, Value};
 use serde::{Deserialize, Serialize};
-use sqlx::{FromRow, PgPool};
+use sqlx::FromRow;
+use ts_rs::TS;
+
+#[derive(TS, Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[ts(export, export_to = "./index.ts")]
+pub enum UserRole {
+  #[default]
+  User,
+  Admin,
+}
+
+impl UserRole {
+  /// Convert an i16 to a UserRole. Required for deserializing from DB.
+  pub fn from_i16(role: i16) -> Result<Self> {
+    match role {
+      0 => Ok(UserRole::User),
+      1 => Ok(UserRole::Admin),
+      _ => Err(Error::InvalidRole),
+    }
+  }
+}
+
+impl TryFrom<i16> for UserRole {
+  type Error = Error;
+
+  fn try_from(role: i16) -> Result<Self> {
+    Self::from_i16(role)
+  }
+}
+
+impl From<UserRole> for Value {
+  fn from(role: UserRole) -> Self {
+    Value::SmallInt(Some(role as i16))
+  }
+}
 
 #[enum_def]
-#[derive(ts_rs::TS, Debug, Deserialize, Serialize, FromRow)]
+#[derive(TS, Debug, Deserialize, Serialize, FromRow)]
 #[ts(export, export_to = "./index.ts")]
 pub struct User {
   pub id: i32,
   pub name: String,
   pub email: String,
   pub password: String,
+  #[sqlx(try_from = "i16")]
+  pub role: UserRole,
+  pub disabled: bool,
+  /// Base32 encoded TOTP secret. Present once 2FA enrollment has started
+  pub totp_secret: Option<String>,
+  pub totp_enabled: bool,
+}
+
+/// One-time 2FA recovery codes. Only SHA-256 hash of the code is stored
+#[derive(Iden)]
+pub enum RecoveryCodeIden {
+  #[iden = "recovery_code"]
+  Table,
+  Id,
+  UserId,
+  CodeHash,
+}
+
+/// Time step of the last accepted TOTP code, stored in the user table. See
+/// [crate::user::two_factor]
+#[derive(Iden)]
+pub enum UserTotpIden {
+  #[iden = "user"]
+  Table,
+  Id,
+  TotpLastStep,
+}
+
+/// Tokens issued before this time are rejected, stored in the user table. See
+/// [crate::user::auth::revoke_tokens]
+#[derive(Iden)]
+pub enum UserTokensIden {
+  #[iden = "user"]
+  Table,
+  Id,
+  TokensRevokedAt,
 }
 
-pub async fn create_tables(pool: &PgPool) -> Result {
+/// Per-user rate limit overrides, stored in the user table. See [crate::user::limits]
+#[derive(Iden)]
+pub enum UserLimitsIden {
+  #[iden = "user"]
+  Table,
+  Id,
+  LimitRequestsPerMinute,
+  LimitConcurrentGenerations,
+  LimitDailyTokens,
+}
+
+/// Statements of the `create_user_tables` migration. See [crate::db::migrations]
+pub fn create_tables() -> Vec<String> {
   let user_table = Table::create()
     .table(UserIden::Table)
     .if_not_exists()
     .col(
-      // not using auto increment. instead we rely on internal counter
+      // not using auto increment. ids come from the cluster wide sequence, see db::next_user_id
       ColumnDef::new(UserIden::Id)
         .integer()
         .not_null()
@@ -31,7 +113,139 @@ pub async fn create_tables(pool: &PgPool) -> Result {
     .col(ColumnDef::new(UserIden::Password).string().not_null())
     .to_string(PostgresQueryBuilder);
 
-  sqlx::query(&user_table).execute(pool).await?;
+  // columns added after the initial release. must be nullable or have defaults
+  let user_table_columns = Table::alter()
+    .table(UserIden::Table)
+    .add_column_if_not_exists(
+      ColumnDef::new(UserIden::Role)
+        .small_integer()
+        .not_null()
+        .default(UserRole::User as i16),
+    )
+    .add_column_if_not_exists(
+      ColumnDef::new(UserIden::Disabled)
+        .boolean()
+        .not_null()
+        .default(false),
+    )
+    .add_column_if_not_exists(ColumnDef::new(UserIden::TotpSecret).string().null())
+    .add_column_if_not_exists(
+      ColumnDef::new(UserIden::TotpEnabled)
+        .boolean()
+        .not_null()
+        .default(false),
+    )
+    .to_string(PostgresQueryBuilder);
+
+  let recovery_code_table = Table::create()
+    .table(RecoveryCodeIden::Table)
+    .if_not_exists()
+    .col(
+      ColumnDef::new(RecoveryCodeIden::Id)
+        .integer()
+        .not_null()
+        .auto_increment()
+        .primary_key(),
+    )
+    .col(
+      ColumnDef::new(RecoveryCodeIden::UserId)
+        .integer()
+        .not_null(),
+    )
+    .col(
+      ColumnDef::new(RecoveryCodeIden::CodeHash)
+        .string()
+        .not_null(),
+    )
+    .foreign_key(
+      ForeignKey::create()
+        .from(RecoveryCodeIden::Table, RecoveryCodeIden::UserId)
+        .to(UserIden::Table, UserIden::Id)
+        .on_delete(sea_query::ForeignKeyAction::Cascade),
+    )
+    .to_string(PostgresQueryBuilder);
+
+  vec![user_table, user_table_columns, recovery_code_table]
+}
+
+pub fn drop_tables() -> Vec<String> {
+  vec![
+    Table::drop()
+      .table(RecoveryCodeIden::Table)
+      .if_exists()
+      .to_string(PostgresQueryBuilder),
+    Table::drop()
+      .table(UserIden::Table)
+      .if_exists()
+      .to_string(PostgresQueryBuilder),
+  ]
+}
+
+/// Statements of the `add_user_limits` migration
+pub fn add_limits() -> Vec<String> {
+  vec![Table::alter()
+    .table(UserLimitsIden::Table)
+    .add_column_if_not_exists(
+      ColumnDef::new(UserLimitsIden::LimitRequestsPerMinute)
+        .integer()
+        .null(),
+    )
+    .add_column_if_not_exists(
+      ColumnDef::new(UserLimitsIden::LimitConcurrentGenerations)
+        .integer()
+        .null(),
+    )
+    .add_column_if_not_exists(
+      ColumnDef::new(UserLimitsIden::LimitDailyTokens)
+        .big_integer()
+        .null(),
+    )
+    .to_string(PostgresQueryBuilder)]
+}
+
+pub fn drop_limits() -> Vec<String> {
+  vec![Table::alter()
+    .table(UserLimitsIden::Table)
+    .drop_column(UserLimitsIden::LimitRequestsPerMinute)
+    .drop_column(UserLimitsIden::LimitConcurrentGenerations)
+    .drop_column(UserLimitsIden::LimitDailyTokens)
+    .to_string(PostgresQueryBuilder)]
+}
+
+/// Statements of the `add_totp_last_step` migration
+pub fn add_totp_last_step() -> Vec<String> {
+  vec![Table::alter()
+    .table(UserTotpIden::Table)
+    .add_column_if_not_exists(
+      ColumnDef::new(UserTotpIden::TotpLastStep)
+        .big_integer()
+        .null(),
+    )
+    .to_string(PostgresQueryBuilder)]
+}
+
+pub fn drop_totp_last_step() -> Vec<String> {
+  vec![Table::alter()
+    .table(UserTotpIden::Table)
+    .drop_column(UserTotpIden::TotpLastStep)
+    .to_string(PostgresQueryBuilder)]
+}
+
+/// Statements of the `add_tokens_revoked_at` migration
+pub fn add_tokens_revoked_at() -> Vec<String> {
+  vec![Table::alter()
+    .table(UserTokensIden::Table)
+    .add_column_if_not_exists(
+      ColumnDef::new(UserTokensIden::TokensRevokedAt)
+        .timestamp_with_time_zone()
+        .null(),
+    )
+    .to_string(PostgresQueryBuilder)]
+}
 
-  Ok(())
+pub fn drop_tokens_revoked_at() -> Vec<String> {
+  vec![Table::alter()
+    .table(UserTokensIden::Table)
+    .drop_column(UserTokensIden::TokensRevokedAt)
+    .to_string(PostgresQueryBuilder)]
 }
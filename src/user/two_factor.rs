@@ -0,0 +1,345 @@
+//! TOTP two-factor authentication routes
+//!
+//! Every TOTP code is accepted only once. The time step of the last accepted code is
+//! stored per user, codes of that or earlier steps are rejected.
+
+use crate::{
+  audit::{schemas::AuditAction, AuditEntry, ClientInfo},
+  db::postgres,
+  extract::Json,
+  jwt::validate_2fa_jwt,
+  result::{Error, Result},
+  user::{
+    auth::Auth,
+    lockout,
+    routes::{find_user_by_id, AuthUser},
+    schemas::{RecoveryCodeIden, User, UserIden, UserTotpIden},
+  },
+};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use sea_query::{Expr, PostgresQueryBuilder, Query};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::query;
+use std::time::{SystemTime, UNIX_EPOCH};
+use totp_rs::{Algorithm, Secret, TOTP};
+use tracing::instrument;
+use ts_rs::TS;
+
+const TOTP_ISSUER: &str = "robo";
+const RECOVERY_CODES_COUNT: usize = 10;
+
+fn totp(secret: &str, email: &str) -> Result<TOTP> {
+  let secret = Secret::Encoded(secret.to_string())
+    .to_bytes()
+    .map_err(|_| Error::TwoFactorNotEnrolled)?;
+
+  let totp = TOTP::new(
+    Algorithm::SHA1,
+    6,
+    1,
+    30,
+    secret,
+    Some(TOTP_ISSUER.to_string()),
+    email.to_string(),
+  )?;
+
+  Ok(totp)
+}
+
+/// Time step the code was generated for, `None` if the code is invalid. Steps within the
+/// skew of the current one are accepted
+fn matching_step(totp: &TOTP, code: &str) -> Option<u64> {
+  let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+  let current = now / totp.step;
+  let skew = totp.skew as u64;
+
+  // check compares in constant time, skew is applied by the loop instead
+  let exact = TOTP {
+    skew: 0,
+    ..totp.clone()
+  };
+
+  (current.saturating_sub(skew)..=current + skew).find(|step| exact.check(code, step * totp.step))
+}
+
+/// Checks TOTP code and marks its time step as used. Fails for codes which were already
+/// used, including concurrent attempts with the same code
+async fn accept_totp(user: &User, secret: &str, code: &str) -> Result<bool> {
+  let Some(step) = matching_step(&totp(secret, &user.email)?, code.trim()) else {
+    return Ok(false);
+  };
+
+  let step = step as i64;
+
+  let use_step_query = Query::update()
+    .table(UserTotpIden::Table)
+    .value(UserTotpIden::TotpLastStep, step)
+    .and_where(Expr::col(UserTotpIden::Id).eq(user.id))
+    .and_where(
+      Expr::col(UserTotpIden::TotpLastStep)
+        .is_null()
+        .or(Expr::col(UserTotpIden::TotpLastStep).lt(step)),
+    )
+    .to_string(PostgresQueryBuilder);
+
+  let db = postgres(user.id)?;
+
+  let res = query(&use_step_query).execute(db).await?;
+
+  Ok(res.rows_affected() > 0)
+}
+
+/// Recovery codes are random enough to not require a slow password hash
+fn hash_recovery_code(code: &str) -> String {
+  let normalized = code.trim().replace('-', "").to_lowercase();
+
+  format!("{:x}", Sha256::digest(normalized))
+}
+
+/// Generates a code like `3f9a1-c07e2`
+fn generate_recovery_code() -> String {
+  let mut bytes = [0u8; 5];
+  OsRng.fill_bytes(&mut bytes);
+
+  let hex: String = bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+
+  format!("{}-{}", &hex[..5], &hex[5..])
+}
+
+/// Checks TOTP code. Falls back to recovery codes which are consumed on use
+async fn verify_code(user: &User, code: &str) -> Result<bool> {
+  let Some(secret) = &user.totp_secret else {
+    return Ok(false);
+  };
+
+  if accept_totp(user, secret, code).await? {
+    return Ok(true);
+  }
+
+  let consume_recovery_code_query = Query::delete()
+    .from_table(RecoveryCodeIden::Table)
+    .and_where(Expr::col(RecoveryCodeIden::UserId).eq(user.id))
+    .and_where(Expr::col(RecoveryCodeIden::CodeHash).eq(hash_recovery_code(code)))
+    .to_string(PostgresQueryBuilder);
+
+  let db = postgres(user.id)?;
+
+  let res = query(&consume_recovery_code_query).execute(db).await?;
+
+  Ok(res.rows_affected() > 0)
+}
+
+#[derive(TS, Debug, Serialize)]
+#[ts(export, export_to = "./index.ts")]
+pub struct TotpEnrollment {
+  /// Base32 encoded secret for manual entry
+  secret: String,
+  /// `otpauth://` URI, usually rendered as QR code
+  provisioning_uri: String,
+}
+
+/// start 2FA enrollment. replaces previously generated but not confirmed secret
+#[instrument(name = "two_factor::enroll")]
+pub async fn enroll(Auth(user_id): Auth) -> Result<Json<TotpEnrollment>> {
+  let user = find_user_by_id(user_id).await?;
+
+  if user.totp_enabled {
+    return Err(Error::TwoFactorEnabled);
+  }
+
+  let Secret::Encoded(secret) = Secret::generate_secret().to_encoded() else {
+    unreachable!("to_encoded always returns encoded secret");
+  };
+
+  let provisioning_uri = totp(&secret, &user.email)?.get_url();
+
+  let set_secret_query = Query::update()
+    .table(UserIden::Table)
+    .value(UserIden::TotpSecret, secret.clone())
+    .and_where(Expr::col(UserIden::Id).eq(user_id))
+    .to_string(PostgresQueryBuilder);
+
+  let db = postgres(user_id)?;
+
+  query(&set_secret_query).execute(db).await?;
+
+  Ok(Json(TotpEnrollment {
+    secret,
+    provisioning_uri,
+  }))
+}
+
+#[derive(TS, Debug, Deserialize)]
+#[ts(export, export_to = "./index.ts")]
+pub struct TwoFactorCodeRequest {
+  /// TOTP code. Recovery codes are also accepted where noted
+  code: String,
+}
+
+#[derive(TS, Debug, Serialize)]
+#[ts(export, export_to = "./index.ts")]
+pub struct RecoveryCodes {
+  /// Shown only once
+  codes: Vec<String>,
+}
+
+/// confirm 2FA enrollment with a TOTP code. returns recovery codes
+#[instrument(name = "two_factor::confirm", skip(req))]
+pub async fn confirm(
+  Auth(user_id): Auth,
+  client: ClientInfo,
+  Json(req): Json<TwoFactorCodeRequest>,
+) -> Result<Json<RecoveryCodes>> {
+  let user = find_user_by_id(user_id).await?;
+
+  if user.totp_enabled {
+    return Err(Error::TwoFactorEnabled);
+  }
+
+  let Some(secret) = &user.totp_secret else {
+    return Err(Error::TwoFactorNotEnrolled);
+  };
+
+  // recovery codes are not valid here, user must prove the authenticator works
+  if !accept_totp(&user, secret, &req.code).await? {
+    return Err(Error::Unauthorized);
+  }
+
+  let codes: Vec<String> = (0..RECOVERY_CODES_COUNT)
+    .map(|_| generate_recovery_code())
+    .collect();
+
+  let enable_query = Query::update()
+    .table(UserIden::Table)
+    .value(UserIden::TotpEnabled, true)
+    .and_where(Expr::col(UserIden::Id).eq(user_id))
+    .to_string(PostgresQueryBuilder);
+
+  let delete_codes_query = Query::delete()
+    .from_table(RecoveryCodeIden::Table)
+    .and_where(Expr::col(RecoveryCodeIden::UserId).eq(user_id))
+    .to_string(PostgresQueryBuilder);
+
+  let insert_codes_query = {
+    let mut insert_codes_query = Query::insert();
+
+    insert_codes_query
+      .into_table(RecoveryCodeIden::Table)
+      .columns([RecoveryCodeIden::UserId, RecoveryCodeIden::CodeHash]);
+
+    for code in &codes {
+      insert_codes_query.values_panic([user_id.into(), hash_recovery_code(code).into()]);
+    }
+
+    insert_codes_query.to_string(PostgresQueryBuilder)
+  };
+
+  let db = postgres(user_id)?;
+  let mut tx = db.begin().await?;
+
+  query(&enable_query).execute(&mut *tx).await?;
+  query(&delete_codes_query).execute(&mut *tx).await?;
+  query(&insert_codes_query).execute(&mut *tx).await?;
+
+  tx.commit().await?;
+
+  AuditEntry::new(user_id, AuditAction::TwoFactorEnabled)
+    .record(&client)
+    .await;
+
+  Ok(Json(RecoveryCodes { codes }))
+}
+
+/// disable 2FA. accepts TOTP or recovery code
+#[instrument(name = "two_factor::disable", skip(req))]
+pub async fn disable(
+  Auth(user_id): Auth,
+  client: ClientInfo,
+  Json(req): Json<TwoFactorCodeRequest>,
+) -> Result<()> {
+  let user = find_user_by_id(user_id).await?;
+
+  if !user.totp_enabled {
+    return Err(Error::TwoFactorNotEnrolled);
+  }
+
+  if !verify_code(&user, &req.code).await? {
+    return Err(Error::Unauthorized);
+  }
+
+  let disable_query = Query::update()
+    .table(UserIden::Table)
+    .value(UserIden::TotpEnabled, false)
+    .value(UserIden::TotpSecret, Option::<String>::None)
+    .value(UserTotpIden::TotpLastStep, Option::<i64>::None)
+    .and_where(Expr::col(UserIden::Id).eq(user_id))
+    .to_string(PostgresQueryBuilder);
+
+  let delete_codes_query = Query::delete()
+    .from_table(RecoveryCodeIden::Table)
+    .and_where(Expr::col(RecoveryCodeIden::UserId).eq(user_id))
+    .to_string(PostgresQueryBuilder);
+
+  let db = postgres(user_id)?;
+  let mut tx = db.begin().await?;
+
+  query(&disable_query).execute(&mut *tx).await?;
+  query(&delete_codes_query).execute(&mut *tx).await?;
+
+  tx.commit().await?;
+
+  AuditEntry::new(user_id, AuditAction::TwoFactorDisabled)
+    .record(&client)
+    .await;
+
+  Ok(())
+}
+
+#[derive(TS, Debug, Deserialize)]
+#[ts(export, export_to = "./index.ts")]
+pub struct TwoFactorVerifyRequest {
+  /// Token returned by login
+  two_factor_token: String,
+  /// TOTP or recovery code
+  code: String,
+}
+
+/// exchange 2FA pending token and code for a real token
+#[instrument(name = "two_factor::verify", skip(req))]
+pub async fn verify(
+  client: ClientInfo,
+  Json(req): Json<TwoFactorVerifyRequest>,
+) -> Result<Json<AuthUser>> {
+  let Ok(user_id) = validate_2fa_jwt(&req.two_factor_token) else {
+    return Err(Error::Unauthorized);
+  };
+
+  let user = find_user_by_id(user_id).await?;
+
+  if user.disabled {
+    return Err(Error::AccountDisabled);
+  }
+
+  // codes are guessed with the same lockout as passwords
+  lockout::check(&user.email, client.ip).await?;
+
+  if !user.totp_enabled || !verify_code(&user, &req.code).await? {
+    lockout::register_failure(&user.email, client.ip).await;
+
+    AuditEntry::new(user.id, AuditAction::TwoFactorFailed)
+      .record(&client)
+      .await;
+
+    return Err(Error::Unauthorized);
+  }
+
+  lockout::register_success(&user.email).await;
+
+  AuditEntry::new(user.id, AuditAction::Login)
+    .details("2fa")
+    .record(&client)
+    .await;
+
+  Ok(Json(user.into()))
+}